@@ -1,8 +1,12 @@
 use anyhow::Result;
 
-pub async fn get_did_and_auth_endpoint(handle: &str) -> Result<(String, String)> {
-    let did = resolve_handle(handle).await?;
-    let pds = get_pds(&did).await?;
+use crate::did::Did;
+use crate::http::Http;
+
+pub const MAX_HANDLE_LEN: usize = 253;
+
+// the authorization server (entryway) the PDS defers to
+pub async fn get_auth_endpoint(http: &Http, pds: &str) -> Result<String> {
     let url = format!("{}/.well-known/oauth-protected-resource", pds);
 
     #[derive(serde::Deserialize)]
@@ -10,41 +14,12 @@ pub async fn get_did_and_auth_endpoint(handle: &str) -> Result<(String, String)>
         authorization_servers: Vec<String>,
     }
 
-    let auth_endpoint: ProtectedResource = reqwest::get(&url).await?.json().await?;
-    Ok((
-        did,
-        auth_endpoint
-            .authorization_servers
-            .first()
-            .cloned()
-            .ok_or(anyhow::anyhow!("auth endpoint not found"))?,
-    ))
-}
-
-pub async fn get_pds(did: &str) -> Result<String> {
-    let did_doc = get_did_doc(did).await?;
-    Ok(did_doc
-        .service
-        .iter()
-        .find(|s| s.id == "#atproto_pds" && s.r#type == "AtprotoPersonalDataServer")
-        .ok_or(anyhow::anyhow!("pds not found"))?
-        .service_endpoint
-        .clone())
-}
-
-pub async fn resolve_handle(handle: &str) -> Result<String> {
-    let url = format!(
-        "https://public.api.bsky.app/xrpc/com.atproto.identity.resolveHandle?handle={}",
-        handle
-    );
-
-    #[derive(serde::Deserialize)]
-    struct HandleResolution {
-        did: String,
-    }
-
-    let did_doc: HandleResolution = reqwest::get(&url).await?.json().await?;
-    Ok(did_doc.did)
+    let auth_endpoint: ProtectedResource = http.get(&url).await?.json().await?;
+    auth_endpoint
+        .authorization_servers
+        .first()
+        .cloned()
+        .ok_or(anyhow::anyhow!("auth endpoint not found"))
 }
 
 #[derive(serde::Deserialize)]
@@ -54,6 +29,22 @@ pub struct DidDoc {
     pub service: Vec<Service>,
 }
 
+impl DidDoc {
+    pub fn pds(&self) -> Option<&str> {
+        self.service
+            .iter()
+            .find(|s| s.id == "#atproto_pds" && s.r#type == "AtprotoPersonalDataServer")
+            .map(|s| s.service_endpoint.as_str())
+    }
+
+    // the handle it claims, which only counts once it resolves back to the DID
+    pub fn handle(&self) -> Option<&str> {
+        self.also_known_as
+            .iter()
+            .find_map(|aka| aka.strip_prefix("at://"))
+    }
+}
+
 #[derive(serde::Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Service {
@@ -62,12 +53,6 @@ pub struct Service {
     pub service_endpoint: String,
 }
 
-pub async fn get_did_doc(did: &str) -> Result<DidDoc> {
-    let url = match &did[..8] {
-        "did:plc:" => format!("https://plc.directory/{did}"),
-        "did:web:" => format!("https://{}/.well-known/did.json", &did[8..]),
-        _ => anyhow::bail!("invalid did"),
-    };
-
-    Ok(reqwest::get(&url).await?.json().await?)
+pub async fn get_did_doc(http: &Http, plc_url: &str, did: &Did) -> Result<DidDoc> {
+    Ok(http.get(&did.document_url(plc_url)?).await?.json().await?)
 }
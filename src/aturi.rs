@@ -0,0 +1,240 @@
+use anyhow::Result;
+
+use crate::did::Did;
+
+// reverse domain authority and a name, e.g. social.psky.chat.room
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Nsid(String);
+
+impl Nsid {
+    pub fn parse(nsid: &str) -> Result<Nsid> {
+        let segments = nsid.split('.').collect::<Vec<_>>();
+        let (name, authority) = segments
+            .split_last()
+            .ok_or(anyhow::anyhow!("Invalid NSID {nsid}"))?;
+
+        let valid = nsid.len() <= 317
+            && authority.len() >= 2
+            && authority.iter().all(|segment| {
+                (1..=63).contains(&segment.len())
+                    && !segment.starts_with('-')
+                    && !segment.ends_with('-')
+                    && segment
+                        .bytes()
+                        .all(|b| b.is_ascii_alphanumeric() || b == b'-')
+            })
+            && !authority[0].starts_with(|c: char| c.is_ascii_digit())
+            && (1..=63).contains(&name.len())
+            && name.starts_with(|c: char| c.is_ascii_alphabetic())
+            && name.bytes().all(|b| b.is_ascii_alphanumeric());
+
+        if !valid {
+            anyhow::bail!("Invalid NSID {nsid}");
+        }
+        Ok(Nsid(nsid.to_string()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::fmt::Display for Nsid {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct RecordKey(String);
+
+impl RecordKey {
+    pub fn parse(rkey: &str) -> Result<RecordKey> {
+        let valid = (1..=512).contains(&rkey.len())
+            && rkey != "."
+            && rkey != ".."
+            && rkey
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b"._~:-".contains(&b));
+
+        if !valid {
+            anyhow::bail!("Invalid record key {rkey}");
+        }
+        Ok(RecordKey(rkey.to_string()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::fmt::Display for RecordKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+// at://<did>[/<collection>[/<rkey>]], records are only ever referred to by
+// DID here, so handle authorities are rejected
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct AtUri {
+    pub authority: Did,
+    pub collection: Option<Nsid>,
+    pub rkey: Option<RecordKey>,
+}
+
+impl AtUri {
+    pub fn parse(uri: &str) -> Result<AtUri> {
+        let rest = uri
+            .strip_prefix("at://")
+            .ok_or(anyhow::anyhow!("Invalid AT-URI {uri}"))?;
+        if rest.contains(['?', '#']) {
+            anyhow::bail!("Invalid AT-URI {uri}, queries and fragments aren't supported");
+        }
+
+        let mut parts = rest.split('/');
+        let authority = Did::parse(parts.next().unwrap_or_default())?;
+        let collection = parts.next().map(Nsid::parse).transpose()?;
+        let rkey = parts.next().map(RecordKey::parse).transpose()?;
+        if parts.next().is_some() {
+            anyhow::bail!("Invalid AT-URI {uri}");
+        }
+
+        Ok(AtUri {
+            authority,
+            collection,
+            rkey,
+        })
+    }
+
+    pub fn record(authority: Did, collection: Nsid, rkey: RecordKey) -> AtUri {
+        AtUri {
+            authority,
+            collection: Some(collection),
+            rkey: Some(rkey),
+        }
+    }
+
+    // a whole repo, for DMs the recipient's
+    pub fn repo(authority: Did) -> AtUri {
+        AtUri {
+            authority,
+            collection: None,
+            rkey: None,
+        }
+    }
+}
+
+impl std::fmt::Display for AtUri {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "at://{}", self.authority)?;
+        if let Some(collection) = &self.collection {
+            write!(f, "/{collection}")?;
+            if let Some(rkey) = &self.rkey {
+                write!(f, "/{rkey}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl serde::Serialize for AtUri {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for AtUri {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<AtUri, D::Error> {
+        let uri = String::deserialize(deserializer)?;
+        AtUri::parse(&uri).map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROOM: &str = "at://did:plc:psky/social.psky.chat.room/general";
+
+    #[test]
+    fn parses_uris() {
+        let uri = AtUri::parse(ROOM).unwrap();
+        assert_eq!(uri.authority, *"did:plc:psky");
+        assert_eq!(
+            uri.collection.as_ref().unwrap().as_str(),
+            "social.psky.chat.room"
+        );
+        assert_eq!(uri.rkey.as_ref().unwrap().as_str(), "general");
+        assert_eq!(uri.to_string(), ROOM);
+
+        for uri in [
+            "at://did:plc:psky",
+            "at://did:web:localhost%3A8080/social.psky.chat.room",
+            "at://did:plc:psky/social.psky.chat.room/3l5z2abc:d~e_f-g.h",
+        ] {
+            assert_eq!(AtUri::parse(uri).unwrap().to_string(), uri);
+        }
+    }
+
+    #[test]
+    fn rejects_bad_uris() {
+        for uri in [
+            "",
+            "did:plc:psky/social.psky.chat.room/general",
+            "https://did:plc:psky/social.psky.chat.room/general",
+            "at://",
+            "at://psky.social/social.psky.chat.room/general",
+            "at://did:plc:/social.psky.chat.room/general",
+            "at://did:plc:psky/",
+            "at://did:plc:psky/social.psky.chat.room/",
+            "at://did:plc:psky/social.psky.chat.room/general/more",
+            "at://did:plc:psky/social.psky.chat.room/general?x=1",
+            "at://did:plc:psky/social.psky.chat.room/general#x",
+        ] {
+            assert!(AtUri::parse(uri).is_err(), "{uri}");
+        }
+    }
+
+    #[test]
+    fn nsids() {
+        for nsid in ["social.psky.chat.room", "app.bsky.feed.post", "a-b.c.d3"] {
+            assert_eq!(Nsid::parse(nsid).unwrap().as_str(), nsid);
+        }
+        for nsid in [
+            "",
+            "room",
+            "psky.room",
+            "social..chat.room",
+            "1social.psky.room",
+            "-social.psky.room",
+            "social.psky-.room",
+            "social.psky.chat.3room",
+            "social.psky.chat.room-name",
+            "social.psky.chat.room_name",
+            "social.psky.chat.",
+            &format!("social.{}.room", "a".repeat(64)),
+        ] {
+            assert!(Nsid::parse(nsid).is_err(), "{nsid}");
+        }
+    }
+
+    #[test]
+    fn record_keys() {
+        for rkey in ["general", "self", "3l5z2abc", "a.b", "..a", "a:b~c_d-e"] {
+            assert_eq!(RecordKey::parse(rkey).unwrap().as_str(), rkey);
+        }
+        for rkey in ["", ".", "..", "a/b", "a b", "a%20b", &"a".repeat(513)] {
+            assert!(RecordKey::parse(rkey).is_err(), "{rkey}");
+        }
+    }
+
+    #[test]
+    fn serde_round_trip() {
+        let uri = AtUri::parse(ROOM).unwrap();
+        let json = serde_json::to_string(&uri).unwrap();
+        assert_eq!(json, format!("\"{ROOM}\""));
+        assert_eq!(serde_json::from_str::<AtUri>(&json).unwrap(), uri);
+        assert!(serde_json::from_str::<AtUri>("\"at://psky.social\"").is_err());
+    }
+}
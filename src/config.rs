@@ -6,13 +6,22 @@ use std::fs::File;
 use std::io;
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio_rustls::{rustls, TlsAcceptor};
 
+use crate::ratelimit::TokenBucket;
+
 #[derive(serde::Deserialize, Clone, Debug)]
 pub struct Settings {
     pub jetstream: JetstreamSettings,
     pub psky: PskySettings,
     pub irc: IrcSettings,
+    // OAuth login is off without this
+    pub oauth: Option<OAuthSettings>,
+    // sessions are only kept in memory without this
+    pub sessions: Option<SessionSettings>,
+    #[serde(default)]
+    pub atproto: AtprotoSettings,
 }
 
 pub fn get_config() -> Result<Settings, config::ConfigError> {
@@ -26,7 +35,9 @@ pub fn get_config() -> Result<Settings, config::ConfigError> {
                 .separator("__"),
         )
         .build()?;
-    settings.try_deserialize::<Settings>()
+    let settings = settings.try_deserialize::<Settings>()?;
+    settings.irc.flood.validate()?;
+    Ok(settings)
 }
 
 #[derive(serde::Deserialize, Clone, Debug)]
@@ -36,9 +47,156 @@ pub struct JetstreamSettings {
     pub port: u16,
 }
 
+#[derive(serde::Deserialize, Clone, Debug)]
+pub struct AtprotoSettings {
+    // DID documents of did:plc accounts are fetched from here
+    #[serde(default = "default_plc_url")]
+    pub plc_url: String,
+    // handles are resolved over DNS and HTTPS, asking the AppView only when
+    // both fail, if this is on
+    #[serde(default)]
+    pub appview_fallback: bool,
+    #[serde(default = "default_appview_url")]
+    pub appview_url: String,
+    // how long resolved handles, DID documents and profiles are kept, and
+    // failed lookups before they're retried
+    #[serde(
+        default = "default_cache_ttl",
+        deserialize_with = "deserialize_number_from_string"
+    )]
+    pub cache_ttl: u64,
+    #[serde(
+        default = "default_negative_cache_ttl",
+        deserialize_with = "deserialize_number_from_string"
+    )]
+    pub negative_cache_ttl: u64,
+    // entries per cache
+    #[serde(
+        default = "default_cache_size",
+        deserialize_with = "deserialize_number_from_string"
+    )]
+    pub cache_size: usize,
+    // seconds
+    #[serde(
+        default = "default_timeout",
+        deserialize_with = "deserialize_number_from_string"
+    )]
+    pub timeout: u64,
+    // how many times a failed lookup is tried again
+    #[serde(
+        default = "default_retries",
+        deserialize_with = "deserialize_number_from_string"
+    )]
+    pub retries: u32,
+    #[serde(default = "default_user_agent")]
+    pub user_agent: String,
+}
+
+impl Default for AtprotoSettings {
+    fn default() -> Self {
+        Self {
+            plc_url: default_plc_url(),
+            appview_fallback: false,
+            appview_url: default_appview_url(),
+            cache_ttl: default_cache_ttl(),
+            negative_cache_ttl: default_negative_cache_ttl(),
+            cache_size: default_cache_size(),
+            timeout: default_timeout(),
+            retries: default_retries(),
+            user_agent: default_user_agent(),
+        }
+    }
+}
+
+impl AtprotoSettings {
+    pub fn cache_ttl(&self) -> Duration {
+        Duration::from_secs(self.cache_ttl)
+    }
+
+    pub fn negative_cache_ttl(&self) -> Duration {
+        Duration::from_secs(self.negative_cache_ttl)
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout)
+    }
+}
+
+fn default_plc_url() -> String {
+    "https://plc.directory".to_string()
+}
+
+fn default_appview_url() -> String {
+    "https://public.api.bsky.app".to_string()
+}
+
+fn default_cache_ttl() -> u64 {
+    60 * 60
+}
+
+fn default_negative_cache_ttl() -> u64 {
+    60
+}
+
+fn default_cache_size() -> usize {
+    10_000
+}
+
+fn default_timeout() -> u64 {
+    10
+}
+
+fn default_retries() -> u32 {
+    2
+}
+
+fn default_user_agent() -> String {
+    format!("ircsky/{}", env!("CARGO_PKG_VERSION"))
+}
+
+#[derive(serde::Deserialize, Clone, Debug)]
+pub struct OAuthSettings {
+    // where the callback listener is reachable from browsers, the client
+    // metadata and redirect URLs are built from it
+    pub public_url: String,
+    pub host: String,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub port: u16,
+    #[serde(
+        default = "default_max_pending_per_ip",
+        deserialize_with = "deserialize_number_from_string"
+    )]
+    pub max_pending_per_ip: usize,
+}
+
+fn default_max_pending_per_ip() -> usize {
+    3
+}
+
+#[derive(serde::Deserialize, Clone, Debug)]
+pub struct SessionSettings {
+    pub dir: PathBuf,
+    // base64 encoded 32 byte key, sessions are encrypted with it
+    pub key: String,
+}
+
+impl SessionSettings {
+    pub fn key(&self) -> anyhow::Result<[u8; 32]> {
+        use base64::Engine;
+
+        base64::engine::general_purpose::STANDARD
+            .decode(self.key.trim())?
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("Session key has to be 32 bytes"))
+    }
+}
+
 #[derive(serde::Deserialize, Clone, Debug)]
 pub struct PskySettings {
     pub general: String,
+    // collection DMs to users without an IRC session are published to, if any
+    #[serde(default)]
+    pub dm_collection: Option<String>,
 }
 
 #[derive(serde::Deserialize, Clone, Debug)]
@@ -48,9 +206,132 @@ pub struct IrcSettings {
     pub port: u16,
     pub tls: TlsSettings,
     pub motd: Option<String>,
+    #[serde(
+        default = "default_sendq",
+        deserialize_with = "deserialize_number_from_string"
+    )]
+    pub sendq: usize,
+    #[serde(
+        default = "default_linelen",
+        deserialize_with = "deserialize_number_from_string"
+    )]
+    pub linelen: usize,
+    #[serde(
+        default = "default_multiline_max_bytes",
+        deserialize_with = "deserialize_number_from_string"
+    )]
+    pub multiline_max_bytes: usize,
+    #[serde(
+        default = "default_multiline_max_lines",
+        deserialize_with = "deserialize_number_from_string"
+    )]
+    pub multiline_max_lines: usize,
+    #[serde(
+        default = "default_ping_interval",
+        deserialize_with = "deserialize_number_from_string"
+    )]
+    pub ping_interval: u64,
+    #[serde(
+        default = "default_ping_timeout",
+        deserialize_with = "deserialize_number_from_string"
+    )]
+    pub ping_timeout: u64,
+    #[serde(
+        default = "default_registration_timeout",
+        deserialize_with = "deserialize_number_from_string"
+    )]
+    pub registration_timeout: u64,
+    #[serde(
+        default = "default_chathistory",
+        deserialize_with = "deserialize_number_from_string"
+    )]
+    pub chathistory: usize,
+    #[serde(default = "default_network")]
+    pub network: String,
+    #[serde(
+        default = "default_dm_queue",
+        deserialize_with = "deserialize_number_from_string"
+    )]
+    pub dm_queue: usize,
+    #[serde(
+        default = "default_dm_queue_expiry",
+        deserialize_with = "deserialize_number_from_string"
+    )]
+    pub dm_queue_expiry: u64,
+    #[serde(
+        default = "default_dm_queue_recipients",
+        deserialize_with = "deserialize_number_from_string"
+    )]
+    pub dm_queue_recipients: usize,
+    #[serde(default)]
+    pub flood: FloodSettings,
+}
+
+fn default_sendq() -> usize {
+    1024 * 1024
+}
+
+fn default_linelen() -> usize {
+    512
+}
+
+fn default_multiline_max_bytes() -> usize {
+    4096
+}
+
+fn default_multiline_max_lines() -> usize {
+    100
+}
+
+fn default_chathistory() -> usize {
+    100
+}
+
+fn default_network() -> String {
+    "ircsky".to_string()
+}
+
+fn default_dm_queue() -> usize {
+    50
+}
+
+fn default_dm_queue_expiry() -> u64 {
+    7 * 24 * 60 * 60
+}
+
+fn default_dm_queue_recipients() -> usize {
+    10000
+}
+
+fn default_ping_interval() -> u64 {
+    120
+}
+
+fn default_ping_timeout() -> u64 {
+    60
+}
+
+fn default_registration_timeout() -> u64 {
+    60
 }
 
 impl IrcSettings {
+    pub fn ping_interval(&self) -> Duration {
+        Duration::from_secs(self.ping_interval)
+    }
+
+    pub fn ping_timeout(&self) -> Duration {
+        Duration::from_secs(self.ping_timeout)
+    }
+
+    pub fn registration_timeout(&self) -> Duration {
+        Duration::from_secs(self.registration_timeout)
+    }
+
+    pub fn dm_queue_expiry(&self) -> Duration {
+        Duration::from_secs(self.dm_queue_expiry)
+    }
+
     pub fn motd(&self) -> Option<String> {
         match self.motd {
             Some(ref motd) => {
@@ -65,6 +346,63 @@ impl IrcSettings {
     }
 }
 
+#[derive(serde::Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct FloodSettings {
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub command_burst: f64,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub command_rate: f64,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_lag: u64,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub publish_burst: f64,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub publish_rate: f64,
+}
+
+impl Default for FloodSettings {
+    fn default() -> Self {
+        Self {
+            command_burst: 20.0,
+            command_rate: 2.0,
+            max_lag: 30,
+            publish_burst: 5.0,
+            publish_rate: 0.5,
+        }
+    }
+}
+
+impl FloodSettings {
+    pub fn command_bucket(&self) -> TokenBucket {
+        TokenBucket::new(self.command_burst, self.command_rate)
+    }
+
+    pub fn publish_bucket(&self) -> TokenBucket {
+        TokenBucket::new(self.publish_burst, self.publish_rate)
+    }
+
+    pub fn max_lag(&self) -> Duration {
+        Duration::from_secs(self.max_lag)
+    }
+
+    // a bucket that never refills would be in debt forever
+    fn validate(&self) -> Result<(), config::ConfigError> {
+        let rates = [
+            ("command_rate", self.command_rate),
+            ("publish_rate", self.publish_rate),
+        ];
+        for (name, rate) in rates {
+            if !(rate.is_finite() && rate > 0.0) {
+                return Err(config::ConfigError::Message(format!(
+                    "irc.flood.{name} has to be more than 0"
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
 #[derive(serde::Deserialize, Clone, Debug)]
 pub struct TlsSettings {
     pub enabled: bool,
@@ -79,10 +417,7 @@ fn load_certs(path: &PathBuf) -> io::Result<Vec<CertificateDer<'static>>> {
 fn load_key(path: &PathBuf) -> io::Result<PrivateKeyDer<'static>> {
     private_key(&mut io::BufReader::new(File::open(path)?))
         .unwrap()
-        .ok_or(io::Error::new(
-            io::ErrorKind::Other,
-            "no private key found".to_string(),
-        ))
+        .ok_or(io::Error::other("no private key found".to_string()))
 }
 
 impl TlsSettings {
@@ -0,0 +1,225 @@
+use anyhow::Result;
+use std::borrow::Borrow;
+use std::ops::Deref;
+
+// did:<method>:<identifier>, checked once where it comes in from IRC, the
+// firehose or a lookup, and passed around as is after that
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, serde::Serialize)]
+#[serde(transparent)]
+pub struct Did(String);
+
+// atproto caps DIDs at 2KB
+const MAX_DID_LEN: usize = 2048;
+
+impl Did {
+    pub fn parse(did: &str) -> Result<Did> {
+        let (method, identifier) = did
+            .strip_prefix("did:")
+            .and_then(|rest| rest.split_once(':'))
+            .ok_or(anyhow::anyhow!("Invalid DID {did}"))?;
+
+        let valid = did.len() <= MAX_DID_LEN
+            && !method.is_empty()
+            && method.bytes().all(|b| b.is_ascii_lowercase())
+            && !identifier.is_empty()
+            && !identifier.ends_with([':', '%'])
+            && identifier
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b".:_%-".contains(&b))
+            && identifier.split('%').skip(1).all(|escaped| {
+                escaped
+                    .get(..2)
+                    .is_some_and(|hex| hex.bytes().all(|b| b.is_ascii_hexdigit()))
+            });
+
+        if !valid {
+            anyhow::bail!("Invalid DID {did}");
+        }
+        Ok(Did(did.to_string()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn method(&self) -> &str {
+        self.0[4..].split(':').next().unwrap_or_default()
+    }
+
+    pub fn identifier(&self) -> &str {
+        &self.0[4 + self.method().len() + 1..]
+    }
+
+    // where the DID document lives, PLC DIDs on the directory, did:web on the
+    // domain with a port as %3A and any path as further :-separated parts
+    pub fn document_url(&self, plc_url: &str) -> Result<String> {
+        match self.method() {
+            "plc" => Ok(format!("{}/{}", plc_url.trim_end_matches('/'), self.0)),
+            "web" => {
+                let mut parts = self.identifier().split(':');
+                let host = parts
+                    .next()
+                    .unwrap_or_default()
+                    .replace("%3A", ":")
+                    .replace("%3a", ":");
+                let path = parts.collect::<Vec<_>>();
+
+                let (domain, port) = match host.split_once(':') {
+                    Some((domain, port)) => (domain, Some(port)),
+                    None => (host.as_str(), None),
+                };
+                if domain.is_empty()
+                    || !domain
+                        .bytes()
+                        .all(|b| b.is_ascii_alphanumeric() || b == b'.' || b == b'-')
+                    || port.is_some_and(|port| port.parse::<u16>().is_err())
+                    || path
+                        .iter()
+                        .any(|part| part.is_empty() || *part == "." || *part == "..")
+                {
+                    anyhow::bail!("Invalid did:web {}", self.0);
+                }
+
+                Ok(match path.as_slice() {
+                    [] => format!("https://{host}/.well-known/did.json"),
+                    path => format!("https://{host}/{}/did.json", path.join("/")),
+                })
+            }
+            method => anyhow::bail!("Unsupported DID method {method}"),
+        }
+    }
+}
+
+impl std::str::FromStr for Did {
+    type Err = anyhow::Error;
+
+    fn from_str(did: &str) -> Result<Did> {
+        Did::parse(did)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Did {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Did, D::Error> {
+        let did = String::deserialize(deserializer)?;
+        Did::parse(&did).map_err(serde::de::Error::custom)
+    }
+}
+
+impl std::fmt::Display for Did {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Deref for Did {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl PartialEq<str> for Did {
+    fn eq(&self, other: &str) -> bool {
+        self.0 == other
+    }
+}
+
+// so maps keyed by DID can be looked up with a &str
+impl Borrow<str> for Did {
+    fn borrow(&self) -> &str {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_valid_dids() {
+        for did in [
+            "did:plc:z72i7hdynmk6r22z27h6tvur",
+            "did:web:example.com",
+            "did:web:localhost%3A8080",
+            "did:web:example.com:user:alice",
+            "did:example:a.b_c-d%20e",
+        ] {
+            assert_eq!(Did::parse(did).unwrap(), *did, "{did}");
+        }
+
+        let did = Did::parse("did:web:example.com:user").unwrap();
+        assert_eq!(did.method(), "web");
+        assert_eq!(did.identifier(), "example.com:user");
+    }
+
+    #[test]
+    fn rejects_invalid_dids() {
+        let too_long = format!("did:plc:{}", "a".repeat(MAX_DID_LEN));
+        for did in [
+            "",
+            "did:",
+            "did:plc",
+            "did:plc:",
+            "plc:abc",
+            "DID:plc:abc",
+            "did:PLC:abc",
+            "did:pl1:abc",
+            "did:plc:abc:",
+            "did:plc:abc%",
+            "did:plc:abc%2",
+            "did:plc:abc%zz",
+            "did:plc:a b",
+            "did:plc:a/b",
+            &too_long,
+        ] {
+            assert!(Did::parse(did).is_err(), "{did}");
+        }
+    }
+
+    #[test]
+    fn document_urls() {
+        let url = |did: &str| Did::parse(did).unwrap().document_url("https://plc.test/");
+        for (did, expected) in [
+            (
+                "did:plc:z72i7hdynmk6r22z27h6tvur",
+                "https://plc.test/did:plc:z72i7hdynmk6r22z27h6tvur",
+            ),
+            (
+                "did:web:example.com",
+                "https://example.com/.well-known/did.json",
+            ),
+            (
+                "did:web:localhost%3A8080",
+                "https://localhost:8080/.well-known/did.json",
+            ),
+            (
+                "did:web:localhost%3a8080",
+                "https://localhost:8080/.well-known/did.json",
+            ),
+            (
+                "did:web:example.com:user:alice",
+                "https://example.com/user/alice/did.json",
+            ),
+            (
+                "did:web:example.com%3A8443:user",
+                "https://example.com:8443/user/did.json",
+            ),
+        ] {
+            assert_eq!(url(did).unwrap(), expected, "{did}");
+        }
+
+        for did in [
+            "did:web:localhost%3Ahttp",
+            "did:web:localhost%3A99999",
+            "did:web:%3A8080",
+            "did:web:exa_mple.com",
+            "did:web:example.com::alice",
+            "did:web:example.com:..",
+            "did:web:example.com:.",
+            "did:key:z6MkhaXgBZDvotDkL5257faiztiGiC2QtKLGpbnnEGta2doK",
+        ] {
+            assert!(url(did).is_err(), "{did}");
+        }
+    }
+}
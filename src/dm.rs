@@ -0,0 +1,108 @@
+use dashmap::{DashMap, DashSet};
+use std::collections::VecDeque;
+use std::time::Duration;
+use tokio::time::Instant;
+
+use crate::aturi::AtUri;
+use crate::did::Did;
+use crate::ircsky::{ChannelName, Ircsky, User};
+use crate::psky::{self, MessageMeta, PskyEvent};
+
+pub struct QueuedDm {
+    pub from: User,
+    pub content: String,
+    pub meta: MessageMeta,
+    queued_at: Instant,
+}
+
+// DMs for users without an IRC session, until they next log in
+#[derive(Default)]
+pub struct DmQueue {
+    queued: DashMap<String, VecDeque<QueuedDm>>,
+    // DIDs that have logged in over IRC, nobody else would ever collect theirs
+    known: DashSet<String>,
+}
+
+impl DmQueue {
+    pub fn register(&self, did: &str) {
+        if !self.known.contains(did) {
+            self.known.insert(did.to_string());
+        }
+    }
+
+    // drops the oldest message when the recipient's queue is full, false if
+    // queueing is turned off, they've never logged in over IRC or there are
+    // already queues for `recipients` others
+    pub fn push(
+        &self,
+        to: &str,
+        from: User,
+        content: String,
+        meta: MessageMeta,
+        limit: usize,
+        recipients: usize,
+    ) -> bool {
+        if limit == 0 || !self.known.contains(to) {
+            return false;
+        }
+        if !self.queued.contains_key(to) && self.queued.len() >= recipients {
+            return false;
+        }
+
+        let mut queue = self.queued.entry(to.to_string()).or_default();
+        while queue.len() >= limit {
+            queue.pop_front();
+        }
+        queue.push_back(QueuedDm {
+            from,
+            content,
+            meta,
+            queued_at: Instant::now(),
+        });
+        true
+    }
+
+    // everything that hasn't expired, oldest first
+    pub fn take(&self, to: &str, expiry: Duration) -> Vec<QueuedDm> {
+        match self.queued.remove(to) {
+            Some((_, queue)) => queue
+                .into_iter()
+                .filter(|dm| dm.queued_at.elapsed() < expiry)
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn prune(&self, expiry: Duration) {
+        self.queued.retain(|_, queue| {
+            queue.retain(|dm| dm.queued_at.elapsed() < expiry);
+            !queue.is_empty()
+        });
+    }
+}
+
+impl Ircsky {
+    // hands a DM to the recipient's IRC session, false if they have none
+    pub fn deliver_dm(&self, from: User, to: &Did, content: String, meta: MessageMeta) -> bool {
+        let (sender, nick) = match self.users.get(to) {
+            Some(user) => match user.sender {
+                Some(ref sender) => (sender.clone(), user.nick()),
+                None => return false,
+            },
+            None => return false,
+        };
+
+        sender
+            .send(PskyEvent::PrivateMessage(
+                from,
+                psky::Message {
+                    r#type: "social.psky.chat.message".to_string(),
+                    content,
+                    room: AtUri::repo(to.clone()),
+                },
+                ChannelName(nick),
+                meta,
+            ))
+            .is_ok()
+    }
+}
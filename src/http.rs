@@ -0,0 +1,46 @@
+use anyhow::Result;
+use std::time::Duration;
+
+use crate::config::AtprotoSettings;
+
+// the one client DID, handle, profile and room lookups go through
+#[derive(Clone)]
+pub struct Http {
+    client: reqwest::Client,
+    retries: u32,
+}
+
+impl Http {
+    pub fn new(settings: &AtprotoSettings) -> Result<Self> {
+        Ok(Self {
+            client: reqwest::Client::builder()
+                .user_agent(&settings.user_agent)
+                .timeout(settings.timeout())
+                .build()?,
+            retries: settings.retries,
+        })
+    }
+
+    // for requests that can't be retried, or for clients that want their own
+    pub fn client(&self) -> &reqwest::Client {
+        &self.client
+    }
+
+    // retried on timeouts, connection errors and server errors, backing off
+    // from 250ms
+    pub async fn get(&self, url: &str) -> Result<reqwest::Response> {
+        let mut attempt = 0;
+        loop {
+            let retry = match self.client.get(url).send().await {
+                Ok(response) if !response.status().is_server_error() => return Ok(response),
+                Ok(response) if attempt >= self.retries => return Ok(response),
+                Err(e) if attempt >= self.retries || !(e.is_timeout() || e.is_connect()) => {
+                    return Err(e.into())
+                }
+                _ => Duration::from_millis(250 << attempt.min(5)),
+            };
+            tokio::time::sleep(retry).await;
+            attempt += 1;
+        }
+    }
+}
@@ -0,0 +1,394 @@
+use anyhow::Result;
+use dashmap::{mapref::entry::Entry, DashMap};
+use futures::future::{BoxFuture, FutureExt, Shared};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+
+use crate::atproto::{self, DidDoc};
+use crate::config::AtprotoSettings;
+use crate::did::Did;
+use crate::ircsky::{ChannelName, Ircsky};
+use crate::psky;
+
+// errors are kept as their message, so they can be cached and shared
+type Lookup<V> = Shared<BoxFuture<'static, Result<V, String>>>;
+
+enum Slot<V> {
+    // everyone asking for the key while it's looked up waits on the same lookup
+    Pending(Lookup<V>),
+    Ready(Result<V, String>, Instant),
+}
+
+// lookups by key, failures are cached too, for less time
+pub struct TtlCache<V> {
+    slots: DashMap<String, Slot<V>>,
+    ttl: Duration,
+    negative_ttl: Duration,
+    capacity: usize,
+}
+
+impl<V> TtlCache<V>
+where
+    V: Clone + Send + Sync + 'static,
+{
+    pub fn new(ttl: Duration, negative_ttl: Duration, capacity: usize) -> Self {
+        Self {
+            slots: DashMap::new(),
+            ttl,
+            negative_ttl,
+            capacity,
+        }
+    }
+
+    pub async fn get_or_fetch(
+        &self,
+        key: &str,
+        fetch: impl Future<Output = Result<V>> + Send + 'static,
+    ) -> Result<V> {
+        let lookup = match self.slots.entry(key.to_string()) {
+            Entry::Occupied(mut entry) => match entry.get() {
+                Slot::Ready(value, expires) if *expires > Instant::now() => {
+                    return value.clone().map_err(|e| anyhow::anyhow!(e));
+                }
+                Slot::Pending(lookup) => lookup.clone(),
+                Slot::Ready(..) => {
+                    let lookup = Self::lookup(fetch);
+                    entry.insert(Slot::Pending(lookup.clone()));
+                    lookup
+                }
+            },
+            Entry::Vacant(entry) => {
+                let lookup = Self::lookup(fetch);
+                entry.insert(Slot::Pending(lookup.clone()));
+                lookup
+            }
+        };
+
+        let value = lookup.clone().await;
+
+        // unless it was invalidated while we waited
+        if let Some(mut slot) = self.slots.get_mut(key) {
+            if matches!(&*slot, Slot::Pending(pending) if pending.ptr_eq(&lookup)) {
+                let ttl = match value {
+                    Ok(_) => self.ttl,
+                    Err(_) => self.negative_ttl,
+                };
+                *slot = Slot::Ready(value.clone(), Instant::now() + ttl);
+            }
+        }
+        self.evict();
+
+        value.map_err(|e| anyhow::anyhow!(e))
+    }
+
+    fn lookup(fetch: impl Future<Output = Result<V>> + Send + 'static) -> Lookup<V> {
+        fetch
+            .map(|value| value.map_err(|e| e.to_string()))
+            .boxed()
+            .shared()
+    }
+
+    // a successful lookup that hasn't expired
+    pub fn is_fresh(&self, key: &str) -> bool {
+        self.slots.get(key).is_some_and(
+            |slot| matches!(&*slot, Slot::Ready(Ok(_), expires) if *expires > Instant::now()),
+        )
+    }
+
+    pub fn insert(&self, key: &str, value: V) {
+        self.slots.insert(
+            key.to_string(),
+            Slot::Ready(Ok(value), Instant::now() + self.ttl),
+        );
+        self.evict();
+    }
+
+    pub fn invalidate(&self, key: &str) {
+        self.slots.remove(key);
+    }
+
+    // expired entries go first, then the ones closest to expiring, down to
+    // 90% so this doesn't run on every insert once full
+    fn evict(&self) {
+        if self.slots.len() <= self.capacity {
+            return;
+        }
+
+        let now = Instant::now();
+        self.slots
+            .retain(|_, slot| !matches!(slot, Slot::Ready(_, expires) if *expires <= now));
+
+        let target = self.capacity - self.capacity / 10;
+        let excess = self.slots.len().saturating_sub(target);
+        if excess == 0 {
+            return;
+        }
+
+        let mut ready = self
+            .slots
+            .iter()
+            .filter_map(|slot| match slot.value() {
+                Slot::Ready(_, expires) => Some((*expires, slot.key().clone())),
+                Slot::Pending(_) => None,
+            })
+            .collect::<Vec<_>>();
+        ready.sort_unstable();
+        for (_, key) in ready.into_iter().take(excess) {
+            self.slots.remove(&key);
+        }
+    }
+}
+
+// handle to DID, DID to document, DID to psky profile
+pub struct IdentityCache {
+    pub handles: TtlCache<Did>,
+    pub docs: TtlCache<Arc<DidDoc>>,
+    pub profiles: TtlCache<Option<psky::Profile>>,
+}
+
+impl IdentityCache {
+    pub fn new(settings: &AtprotoSettings) -> Self {
+        let ttl = settings.cache_ttl();
+        let negative_ttl = settings.negative_cache_ttl();
+        let capacity = settings.cache_size;
+
+        Self {
+            handles: TtlCache::new(ttl, negative_ttl, capacity),
+            docs: TtlCache::new(ttl, negative_ttl, capacity),
+            profiles: TtlCache::new(ttl, negative_ttl, capacity),
+        }
+    }
+}
+
+impl Ircsky {
+    pub async fn resolve_handle(&self, handle: &str) -> Result<Did> {
+        let handle = handle.to_ascii_lowercase();
+        let resolver = self.resolver.clone();
+        let fetch = {
+            let handle = handle.clone();
+            async move { resolver.resolve(&handle).await }
+        };
+        self.identity.handles.get_or_fetch(&handle, fetch).await
+    }
+
+    pub async fn get_did_doc(&self, did: &Did) -> Result<Arc<DidDoc>> {
+        let fetch = {
+            let (http, did) = (self.http.clone(), did.clone());
+            let plc_url = self.config.atproto.plc_url.clone();
+            async move {
+                atproto::get_did_doc(&http, &plc_url, &did)
+                    .await
+                    .map(Arc::new)
+            }
+        };
+        self.identity.docs.get_or_fetch(did, fetch).await
+    }
+
+    pub async fn get_pds(&self, did: &Did) -> Result<String> {
+        self.get_did_doc(did)
+            .await?
+            .pds()
+            .map(str::to_string)
+            .ok_or(anyhow::anyhow!("pds not found"))
+    }
+
+    // None if they have no profile record
+    pub async fn get_profile(&self, did: &str, pds: &str) -> Result<Option<psky::Profile>> {
+        let fetch = {
+            let (http, did, pds) = (self.http.clone(), did.to_string(), pds.to_string());
+            async move {
+                #[derive(serde::Deserialize)]
+                struct GetProfile {
+                    value: psky::Profile,
+                }
+
+                let response = http
+                    .get(&format!(
+                    "{}/xrpc/com.atproto.repo.getRecord?repo={}&collection=social.psky.actor.profile&rkey=self",
+                    pds, did
+                ))
+                .await?;
+                Ok(response.json::<GetProfile>().await.map(|gp| gp.value).ok())
+            }
+        };
+        self.identity.profiles.get_or_fetch(did, fetch).await
+    }
+
+    // the DID's handle changed or their document did, the old handle may now
+    // point elsewhere
+    pub fn invalidate_identity(&self, did: &str, handle: Option<&str>) {
+        let old = self.users.get(did).and_then(|user| user.handle.clone());
+        for handle in old.iter().map(String::as_str).chain(handle) {
+            self.identity
+                .handles
+                .invalidate(&handle.to_ascii_lowercase());
+        }
+        self.identity.docs.invalidate(did);
+    }
+
+    // sets the verified handle of a known user, telling everyone who can see
+    // them if that changes their nick
+    pub fn update_handle(&self, did: &str, handle: Option<String>) {
+        if let Some(handle) = &handle {
+            self.claim_handle(did, handle);
+        }
+
+        let old = self.users.get(did).map(|user| user.nick());
+        self.users.alter(did, |_, mut user| {
+            user.handle = handle;
+            user
+        });
+
+        let user = self.users.get(did).map(|user| user.clone());
+        if let (Some(old), Some(user)) = (old, user) {
+            if old != user.nick() {
+                self.broadcast_to_user_channels(did, |name, names| {
+                    psky::PskyEvent::Nick(user.clone(), old.clone(), name, names)
+                });
+                // their own sessions, even if they're in no channels
+                if let Some(ref sender) = user.sender {
+                    _ = sender.send(psky::PskyEvent::Nick(
+                        user.clone(),
+                        old,
+                        ChannelName(String::new()),
+                        Vec::new(),
+                    ));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::sync::atomic::Ordering;
+
+    use crate::config::Settings;
+    use crate::http::Http;
+    use crate::resolver::{tests::Fake, HandleResolver};
+
+    const ALICE: &str = "did:plc:alice";
+
+    fn cache(capacity: usize) -> TtlCache<Did> {
+        TtlCache::new(Duration::from_secs(60), Duration::from_secs(5), capacity)
+    }
+
+    // a lookup through the resolver that takes a second
+    fn lookup(resolver: &Arc<Fake>) -> impl Future<Output = Result<Did>> + Send + 'static {
+        let resolver = resolver.clone();
+        async move {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            resolver.resolve("alice.test").await
+        }
+    }
+
+    fn calls(resolver: &Fake) -> usize {
+        resolver.calls.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn settings() -> Settings {
+        serde_json::from_value(serde_json::json!({
+            "jetstream": { "host": "localhost", "port": 6008 },
+            "psky": { "general": "at://did:plc:psky/social.psky.chat.room/general" },
+            "irc": { "host": "localhost", "port": 6667, "tls": { "enabled": false } },
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failures_expire_sooner() {
+        let cache = cache(10);
+        let found = Arc::new(Fake::new(Some(ALICE)));
+        let missing = Arc::new(Fake::new(None));
+
+        cache
+            .get_or_fetch("alice.test", lookup(&found))
+            .await
+            .unwrap();
+        assert!(cache
+            .get_or_fetch("bob.test", lookup(&missing))
+            .await
+            .is_err());
+        assert!(cache
+            .get_or_fetch("bob.test", lookup(&missing))
+            .await
+            .is_err());
+        assert_eq!(calls(&missing), 1);
+
+        tokio::time::advance(Duration::from_secs(6)).await;
+        cache
+            .get_or_fetch("alice.test", lookup(&found))
+            .await
+            .unwrap();
+        assert!(cache
+            .get_or_fetch("bob.test", lookup(&missing))
+            .await
+            .is_err());
+        assert_eq!(calls(&found), 1);
+        assert_eq!(calls(&missing), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn concurrent_lookups_share_one_fetch() {
+        let cache = cache(10);
+        let resolver = Arc::new(Fake::new(Some(ALICE)));
+
+        let (first, second) = tokio::join!(
+            cache.get_or_fetch("alice.test", lookup(&resolver)),
+            cache.get_or_fetch("alice.test", lookup(&resolver)),
+        );
+        assert_eq!(first.unwrap(), *ALICE);
+        assert_eq!(second.unwrap(), *ALICE);
+        assert_eq!(calls(&resolver), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn evicts_the_closest_to_expiring() {
+        let cache = cache(10);
+        let did = Did::parse(ALICE).unwrap();
+        for i in 0..=10 {
+            cache.insert(&i.to_string(), did.clone());
+            tokio::time::advance(Duration::from_secs(1)).await;
+        }
+
+        // down to 9 once over capacity
+        assert_eq!(cache.slots.len(), 9);
+        assert!(!cache.is_fresh("0"));
+        assert!(!cache.is_fresh("1"));
+        assert!((2..=10).all(|i| cache.is_fresh(&i.to_string())));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn invalidated_keys_are_fetched_again() {
+        let cache = cache(10);
+        let resolver = Arc::new(Fake::new(Some(ALICE)));
+
+        cache
+            .get_or_fetch("alice.test", lookup(&resolver))
+            .await
+            .unwrap();
+        cache.invalidate("alice.test");
+        assert!(!cache.is_fresh("alice.test"));
+        cache
+            .get_or_fetch("alice.test", lookup(&resolver))
+            .await
+            .unwrap();
+        assert_eq!(calls(&resolver), 2);
+    }
+
+    #[tokio::test]
+    async fn handles_go_through_the_resolver_once() {
+        let config = settings();
+        let http = Http::new(&config.atproto).unwrap();
+        let resolver = Arc::new(Fake::new(Some(ALICE)));
+        let ircsky = Ircsky::with_resolver(config, http, resolver.clone());
+
+        let did = ircsky.resolve_handle("alice.test").await.unwrap();
+        assert_eq!(did, *ALICE);
+        ircsky.resolve_handle("Alice.Test").await.unwrap();
+        assert_eq!(calls(&resolver), 1);
+    }
+}
@@ -0,0 +1,159 @@
+use anyhow::Result;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use irc_rust::{parsed::Parsed, Message};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::irc::{IrcClient, UserState};
+
+// payloads come in 400 byte chunks, a shorter one or + ends them
+const CHUNK_LEN: usize = 400;
+// a handle and an app password, with room to spare
+const MAX_PAYLOAD_LEN: usize = 2048;
+
+// authzid \0 authcid \0 password, acting as someone else isn't supported
+fn parse_plain(payload: &str) -> Option<(String, String)> {
+    let decoded = String::from_utf8(STANDARD.decode(payload).ok()?).ok()?;
+    let mut parts = decoded.split('\0');
+    match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(authzid), Some(authcid), Some(password), None)
+            if authzid.is_empty() || authzid == authcid =>
+        {
+            Some((authcid.to_string(), password.to_string()))
+        }
+        _ => None,
+    }
+}
+
+impl<T> IrcClient<T>
+where
+    T: AsyncRead + AsyncWrite,
+{
+    // SASL PLAIN, the authentication identity is the handle and the password
+    // an app password, the same as with PASS
+    pub async fn handle_authenticate(&mut self, message: Parsed<'_>) -> Result<()> {
+        let param = message
+            .param(0)
+            .or(message.trailing())
+            .ok_or(anyhow::anyhow!("Nothing given with AUTHENTICATE"))?;
+
+        if self.registered() || self.sasl_login.is_some() {
+            self.sasl = None;
+            return self
+                .send_sasl_reply("907", "You have already authenticated using SASL")
+                .await;
+        }
+        if !self.cap.has_capability("sasl") || !matches!(self.user, UserState::New) {
+            return self
+                .send_sasl_reply("904", "SASL authentication failed")
+                .await;
+        }
+
+        if param == "*" {
+            self.sasl = None;
+            return self
+                .send_sasl_reply("906", "SASL authentication aborted")
+                .await;
+        }
+
+        let mut payload = match self.sasl.take() {
+            Some(payload) => payload,
+            None if param.eq_ignore_ascii_case("PLAIN") => {
+                self.sasl = Some(String::new());
+                return self
+                    .send(Message::builder("AUTHENTICATE").param("+").build())
+                    .await;
+            }
+            None => {
+                self.send(
+                    Message::builder("908")
+                        .param(self.user.nick().unwrap_or("*"))
+                        .param("PLAIN")
+                        .trailing("are available SASL mechanisms")
+                        .build(),
+                )
+                .await?;
+                return self
+                    .send_sasl_reply("904", "SASL authentication failed")
+                    .await;
+            }
+        };
+
+        if param != "+" {
+            payload.push_str(param);
+        }
+        if payload.len() > MAX_PAYLOAD_LEN {
+            return self.send_sasl_reply("905", "SASL message too long").await;
+        }
+        if param.len() == CHUNK_LEN {
+            self.sasl = Some(payload);
+            return Ok(());
+        }
+
+        let (handle, password) = match parse_plain(&payload) {
+            Some(credentials) => credentials,
+            None => {
+                return self
+                    .send_sasl_reply("904", "SASL authentication failed")
+                    .await
+            }
+        };
+
+        let (did, session) = match self.password_session(&handle, &password).await {
+            Ok(login) => login,
+            Err(e) => {
+                println!("SASL login for {handle} failed: {e}");
+                return self
+                    .send_sasl_reply("904", "SASL authentication failed")
+                    .await;
+            }
+        };
+
+        let nick = self.user.nick().unwrap_or("*").to_owned();
+        self.send(
+            Message::builder("900")
+                .param(&nick)
+                .param(format!("{nick}!{did}@the.atmosphere"))
+                .param(did.as_str())
+                .trailing(format!("You are now logged in as {handle}"))
+                .build(),
+        )
+        .await?;
+        self.sasl_login = Some((did, session));
+        self.send_sasl_reply("903", "SASL authentication successful")
+            .await
+    }
+
+    async fn send_sasl_reply(&mut self, numeric: &str, text: &str) -> Result<()> {
+        self.send(
+            Message::builder(numeric)
+                .param(self.user.nick().unwrap_or("*"))
+                .trailing(text)
+                .build(),
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_credentials() {
+        let encode = |plain: &str| STANDARD.encode(plain);
+        let credentials = Some(("alice.test".to_string(), "app-password".to_string()));
+
+        assert_eq!(
+            parse_plain(&encode("\0alice.test\0app-password")),
+            credentials
+        );
+        assert_eq!(
+            parse_plain(&encode("alice.test\0alice.test\0app-password")),
+            credentials
+        );
+        assert_eq!(parse_plain(&encode("bob.test\0alice.test\0pw")), None);
+        assert_eq!(parse_plain(&encode("alice.test\0pw")), None);
+        assert_eq!(parse_plain(&encode("\0alice.test\0pw\0extra")), None);
+        assert_eq!(parse_plain("not base64!"), None);
+    }
+}
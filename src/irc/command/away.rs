@@ -0,0 +1,61 @@
+use anyhow::Result;
+use irc_rust::{parsed::Parsed, Message};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::{
+    irc::{IrcClient, WithTags, AWAYLEN},
+    ircsky::User,
+};
+
+impl<T> IrcClient<T>
+where
+    T: AsyncRead + AsyncWrite,
+{
+    pub async fn handle_away(&mut self, message: Parsed<'_>) -> Result<()> {
+        let nick = self.user.get_nick()?.to_owned();
+
+        let away = message
+            .trailing()
+            .or(message.param(0))
+            .filter(|away| !away.is_empty())
+            .map(|away| {
+                let mut end = away.len().min(AWAYLEN);
+                while !away.is_char_boundary(end) {
+                    end -= 1;
+                }
+                away[..end].to_string()
+            });
+
+        // away state lives on the DID, logged out users only get the reply
+        if let Some(did) = self.user.did().cloned() {
+            self.ircsky.users.alter(&did, |_, mut user| {
+                user.away = away.clone();
+                user
+            });
+            self.ircsky.broadcast_away(&did);
+        }
+
+        let reply = match away {
+            Some(_) => Message::builder("306")
+                .param(&nick)
+                .trailing("You have been marked as being away"),
+            None => Message::builder("305")
+                .param(&nick)
+                .trailing("You are no longer marked as being away"),
+        };
+        self.send(reply.build()).await
+    }
+
+    pub async fn send_away_notify(&mut self, user: &User, away: Option<&str>) -> Result<()> {
+        let message = Message::builder("AWAY").tags(&self.user_tags(user)).prefix(
+            user.nick(),
+            Some(&user.did),
+            Some("the.atmosphere"),
+        );
+        let message = match away {
+            Some(away) => message.trailing(away),
+            None => message,
+        };
+        self.send(message.build()).await
+    }
+}
@@ -0,0 +1,172 @@
+use anyhow::Result;
+use irc_rust::{parsed::Parsed, Message};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::irc::{IrcClient, MultilineBatch};
+
+impl<T> IrcClient<T>
+where
+    T: AsyncRead + AsyncWrite,
+{
+    pub async fn handle_batch(&mut self, message: Parsed<'_>) -> Result<()> {
+        if !self.cap.has_capability("draft/multiline") {
+            return self.handle_other(message).await;
+        }
+
+        let reference = message
+            .param(0)
+            .ok_or(anyhow::anyhow!("No reference given with BATCH"))?;
+
+        if let Some(reference) = reference.strip_prefix('+') {
+            let batch_type = message.param(1).unwrap_or("*");
+            if batch_type != "draft/multiline" {
+                return self
+                    .send_batch_fail("UNKNOWN_TYPE", batch_type, "Unsupported batch type")
+                    .await;
+            }
+
+            let target = match message.param(2).or(message.trailing()) {
+                Some(target) => target,
+                None => {
+                    return self
+                        .send_batch_fail(
+                            "MULTILINE_INVALID_TARGET",
+                            "*",
+                            "No target given for multiline batch",
+                        )
+                        .await;
+                }
+            };
+
+            self.multiline = Some(MultilineBatch {
+                reference: reference.to_string(),
+                target: target.to_string(),
+                content: String::new(),
+                lines: 0,
+                failed: false,
+            });
+            return Ok(());
+        }
+
+        let reference = reference.strip_prefix('-').unwrap_or(reference);
+        let batch = match self.multiline.take() {
+            Some(batch) if batch.reference == reference => batch,
+            other => {
+                self.multiline = other;
+                return self
+                    .send_batch_fail("MULTILINE_INVALID", reference, "No such batch open")
+                    .await;
+            }
+        };
+
+        if batch.failed {
+            return Ok(());
+        }
+
+        if batch.content.trim().is_empty() {
+            return self
+                .send_batch_fail(
+                    "MULTILINE_INVALID",
+                    &batch.target,
+                    "Multiline batch is entirely blank",
+                )
+                .await;
+        }
+
+        self.privmsg(batch.target, &batch.content).await
+    }
+
+    pub async fn add_to_multiline(
+        &mut self,
+        reference: &str,
+        target: &str,
+        line: &str,
+        concat: bool,
+    ) -> Result<()> {
+        let config = &self.ircsky.config.irc;
+        let (max_bytes, max_lines) = (config.multiline_max_bytes, config.multiline_max_lines);
+
+        let batch = match self.multiline.as_mut() {
+            Some(batch) if batch.reference == reference => batch,
+            _ => {
+                return self
+                    .send_batch_fail("MULTILINE_INVALID", reference, "No such batch open")
+                    .await;
+            }
+        };
+
+        if batch.failed {
+            return Ok(());
+        }
+
+        if batch.target != target {
+            batch.failed = true;
+            let target = batch.target.clone();
+            return self
+                .send_batch_fail(
+                    "MULTILINE_INVALID_TARGET",
+                    &target,
+                    "Message target doesn't match the batch target",
+                )
+                .await;
+        }
+
+        if concat && line.is_empty() {
+            batch.failed = true;
+            return self
+                .send_batch_fail(
+                    "MULTILINE_INVALID",
+                    reference,
+                    "Concatenated lines can't be blank",
+                )
+                .await;
+        }
+
+        if batch.lines > 0 && !concat {
+            batch.content.push('\n');
+        }
+        batch.content.push_str(line);
+        batch.lines += 1;
+
+        if batch.lines > max_lines {
+            batch.failed = true;
+            return self
+                .send_batch_fail(
+                    "MULTILINE_MAX_LINES",
+                    &max_lines.to_string(),
+                    "Multiline batch max-lines exceeded",
+                )
+                .await;
+        }
+
+        if batch.content.len() > max_bytes {
+            batch.failed = true;
+            return self
+                .send_batch_fail(
+                    "MULTILINE_MAX_BYTES",
+                    &max_bytes.to_string(),
+                    "Multiline batch max-bytes exceeded",
+                )
+                .await;
+        }
+
+        Ok(())
+    }
+
+    async fn send_batch_fail(
+        &mut self,
+        code: &str,
+        context: &str,
+        description: &str,
+    ) -> Result<()> {
+        self.send(
+            Message::builder("FAIL")
+                .param("BATCH")
+                .param(code)
+                .param(context)
+                .trailing(description)
+                .build(),
+        )
+        .await
+    }
+}
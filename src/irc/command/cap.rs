@@ -4,6 +4,21 @@ use tokio::io::{AsyncRead, AsyncWrite};
 
 use crate::irc::{CapState, IrcClient};
 
+const CAPABILITIES: &[&str] = &[
+    "account-notify",
+    "account-tag",
+    "away-notify",
+    "batch",
+    "draft/chathistory",
+    "draft/multiline",
+    "echo-message",
+    "extended-join",
+    "message-tags",
+    "sasl",
+    "server-time",
+    "standard-replies",
+];
+
 impl<T> IrcClient<T>
 where
     T: AsyncRead + AsyncWrite,
@@ -20,21 +35,32 @@ where
                 }
                 self.cap = CapState::Negotiating(Vec::new());
             }
-            CapState::Capabilities(_) => {
-                if subcommand != "LIST" {
-                    anyhow::bail!("You can only send CAP LIST after CAP END");
-                }
+            CapState::Capabilities(_) if subcommand != "LIST" => {
+                anyhow::bail!("You can only send CAP LIST after CAP END");
             }
             _ => {}
         }
 
         match subcommand {
             "LS" => {
+                // values are only understood from CAP LS 302 on
+                let with_values = message
+                    .param(1)
+                    .and_then(|version| version.parse::<u32>().ok())
+                    .is_some_and(|version| version >= 302);
+                let capabilities = CAPABILITIES
+                    .iter()
+                    .map(|cap| match self.capability_value(cap) {
+                        Some(value) if with_values => format!("{cap}={value}"),
+                        _ => cap.to_string(),
+                    })
+                    .collect::<Vec<_>>();
+
                 self.send(
                     Message::builder("CAP")
                         .param("*")
                         .param("LS")
-                        .trailing("echo-message")
+                        .trailing(capabilities.join(" "))
                         .build(),
                 )
                 .await
@@ -57,52 +83,56 @@ where
             "REQ" => {
                 let requested = message
                     .trailing()
-                    .ok_or_else(|| anyhow::anyhow!("Missing requested capability"))?
-                    .split(' ');
+                    .or(message.param(1))
+                    .ok_or_else(|| anyhow::anyhow!("Missing requested capability"))?;
+                let nick = self.user.nick().unwrap_or("*").to_owned();
 
-                let mut ack = Vec::new();
-                let mut nak = Vec::new();
+                // a request is accepted or rejected as a whole
+                let capabilities = requested
+                    .split(' ')
+                    .filter(|cap| !cap.is_empty())
+                    .map(str::to_string)
+                    .collect::<Vec<_>>();
+                let supported = capabilities
+                    .iter()
+                    .all(|cap| CAPABILITIES.contains(&cap.as_str()));
 
-                for capability in requested {
-                    match capability {
-                        "echo-message" => ack.push(capability.to_string()),
-                        _ => nak.push(capability.to_string()),
-                    }
+                if supported {
+                    self.cap.add_capabilities(capabilities)?;
                 }
 
-                if !ack.is_empty() {
-                    self.cap.add_capabilities(ack.clone())?;
-                    self.send(
-                        Message::builder("CAP")
-                            .param(self.user.nick().unwrap())
-                            .param("ACK")
-                            .param(ack.join(" "))
-                            .build(),
-                    )
-                    .await?;
-                }
-
-                if !nak.is_empty() {
-                    self.send(
-                        Message::builder("CAP")
-                            .param(self.user.nick().unwrap())
-                            .param("NAK")
-                            .param(ack.join(" "))
-                            .build(),
-                    )
-                    .await?;
-                }
-
-                Ok(())
+                self.send(
+                    Message::builder("CAP")
+                        .param(nick)
+                        .param(if supported { "ACK" } else { "NAK" })
+                        .trailing(requested)
+                        .build(),
+                )
+                .await
             }
             "END" => match self.cap {
                 CapState::Negotiating(ref mut caps) => {
                     self.cap = CapState::Capabilities(std::mem::take(caps));
-                    Ok(())
+                    match self.pending_nick.take() {
+                        Some(nick) => self.apply_nick(&nick).await,
+                        None => Ok(()),
+                    }
                 }
                 _ => anyhow::bail!("CAP END without CAP LS/REQ"),
             },
             _ => Ok(()),
         }
     }
+
+    fn capability_value(&self, capability: &str) -> Option<String> {
+        match capability {
+            "draft/multiline" => Some(format!(
+                "max-bytes={},max-lines={}",
+                self.ircsky.config.irc.multiline_max_bytes,
+                self.ircsky.config.irc.multiline_max_lines
+            )),
+            "sasl" => Some("PLAIN".to_string()),
+            _ => None,
+        }
+    }
 }
@@ -0,0 +1,335 @@
+use std::collections::VecDeque;
+
+use anyhow::Result;
+use irc_rust::{parsed::Parsed, Message};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::irc::{IrcClient, WithTags};
+use crate::ircsky::{ChannelName, HistoryEntry};
+
+enum MsgRef {
+    Msgid(String),
+    Timestamp(u64),
+}
+
+impl MsgRef {
+    fn parse(msgref: &str) -> Option<MsgRef> {
+        if let Some(msgid) = msgref.strip_prefix("msgid=") {
+            return Some(MsgRef::Msgid(msgid.to_string()));
+        }
+
+        let timestamp = msgref.strip_prefix("timestamp=")?;
+        let time = chrono::DateTime::parse_from_rfc3339(timestamp).ok()?;
+        // anything before the epoch is an invalid reference
+        u64::try_from(time.timestamp_micros())
+            .ok()
+            .map(MsgRef::Timestamp)
+    }
+
+    // the range of entries that are at `self`, as (first at, first after)
+    fn bounds(&self, history: &VecDeque<HistoryEntry>) -> Option<(usize, usize)> {
+        match self {
+            MsgRef::Msgid(msgid) => {
+                let i = history
+                    .iter()
+                    .position(|entry| entry.meta.msgid.as_ref() == Some(msgid))?;
+                Some((i, i + 1))
+            }
+            MsgRef::Timestamp(time) => Some((
+                history.partition_point(|entry| entry.meta.time_us < *time),
+                history.partition_point(|entry| entry.meta.time_us <= *time),
+            )),
+        }
+    }
+}
+
+fn first(
+    history: &VecDeque<HistoryEntry>,
+    from: usize,
+    to: usize,
+    limit: usize,
+) -> Vec<HistoryEntry> {
+    history.range(from..to.min(from + limit)).cloned().collect()
+}
+
+fn last(
+    history: &VecDeque<HistoryEntry>,
+    from: usize,
+    to: usize,
+    limit: usize,
+) -> Vec<HistoryEntry> {
+    history
+        .range(from.max(to.saturating_sub(limit))..to)
+        .cloned()
+        .collect()
+}
+
+// None if a reference couldn't be parsed, an empty list if it isn't in history
+fn select(
+    history: &VecDeque<HistoryEntry>,
+    subcommand: &str,
+    refs: &[&str],
+    limit: usize,
+) -> Option<Vec<HistoryEntry>> {
+    let len = history.len();
+
+    if subcommand == "LATEST" && refs.first() == Some(&"*") {
+        return Some(last(history, 0, len, limit));
+    }
+
+    let bounds = refs
+        .iter()
+        .map(|msgref| Some(MsgRef::parse(msgref)?.bounds(history)))
+        .collect::<Option<Vec<_>>>()?;
+    let bounds = match bounds.into_iter().collect::<Option<Vec<_>>>() {
+        Some(bounds) => bounds,
+        None => return Some(Vec::new()),
+    };
+
+    Some(match (subcommand, bounds.as_slice()) {
+        ("LATEST", [(_, after)]) => last(history, *after, len, limit),
+        ("BEFORE", [(at, _)]) => last(history, 0, *at, limit),
+        ("AFTER", [(_, after)]) => first(history, *after, len, limit),
+        ("AROUND", [(at, _)]) => first(history, at.saturating_sub(limit / 2), len, limit),
+        ("BETWEEN", [(a_at, a_after), (b_at, b_after)]) => {
+            if a_at <= b_at {
+                first(history, *a_after, *b_at, limit)
+            } else {
+                last(history, *b_after, *a_at, limit)
+            }
+        }
+        _ => return None,
+    })
+}
+
+impl<T> IrcClient<T>
+where
+    T: AsyncRead + AsyncWrite,
+{
+    pub async fn handle_chathistory(&mut self, message: Parsed<'_>) -> Result<()> {
+        let params = message
+            .params()
+            .flatten()
+            .copied()
+            .chain(message.trailing())
+            .collect::<Vec<_>>();
+
+        let subcommand = match params.first() {
+            Some(subcommand) => subcommand.to_uppercase(),
+            None => {
+                return self
+                    .send_chathistory_fail("INVALID_PARAMS", &["*"], "Missing subcommand")
+                    .await;
+            }
+        };
+
+        let refs_count = match subcommand.as_str() {
+            "LATEST" | "BEFORE" | "AFTER" | "AROUND" => 1,
+            "BETWEEN" | "TARGETS" => 2,
+            _ => {
+                return self
+                    .send_chathistory_fail("UNKNOWN_COMMAND", &[&subcommand], "Unknown subcommand")
+                    .await;
+            }
+        };
+
+        let target_count = if subcommand == "TARGETS" { 0 } else { 1 };
+        let expected = 1 + target_count + refs_count + 1;
+        let limit = params
+            .get(expected - 1)
+            .and_then(|limit| limit.parse::<usize>().ok());
+        let limit = match limit {
+            Some(limit) if params.len() == expected => {
+                limit.min(self.ircsky.config.irc.chathistory)
+            }
+            _ => {
+                return self
+                    .send_chathistory_fail("INVALID_PARAMS", &[&subcommand], "Invalid parameters")
+                    .await;
+            }
+        };
+
+        if subcommand == "TARGETS" {
+            return self
+                .send_chathistory_targets(params[1], params[2], limit)
+                .await;
+        }
+
+        let target = params[1];
+        let uri = match self
+            .ircsky
+            .resolve_channel(&ChannelName(target.to_string()))
+            .await
+        {
+            Some(uri) => uri,
+            None => {
+                return self
+                    .send_chathistory_fail(
+                        "INVALID_TARGET",
+                        &[&subcommand, target],
+                        "No such channel",
+                    )
+                    .await;
+            }
+        };
+
+        let entries = match self.ircsky.channels.get(&uri) {
+            Some(channel) => select(
+                &channel.history,
+                &subcommand,
+                &params[2..2 + refs_count],
+                limit,
+            ),
+            None => Some(Vec::new()),
+        };
+
+        match entries {
+            Some(entries) => self.send_history(target, entries).await,
+            None => {
+                self.send_chathistory_fail(
+                    "INVALID_PARAMS",
+                    &[&subcommand],
+                    "Invalid message reference",
+                )
+                .await
+            }
+        }
+    }
+
+    async fn send_history(&mut self, target: &str, entries: Vec<HistoryEntry>) -> Result<()> {
+        let batch = self.open_batch(&["chathistory", target]).await?;
+
+        for entry in entries {
+            let mut tags = self.message_tags(&entry.user, &entry.meta);
+            if let Some(ref batch) = batch {
+                tags.push(("batch", batch.clone()));
+            }
+
+            let privmsg = Message::builder("PRIVMSG")
+                .tags(&tags)
+                .prefix(
+                    entry.user.nick(),
+                    Some(&entry.user.did),
+                    Some("the.atmosphere"),
+                )
+                .param(target);
+
+            for line in entry
+                .content
+                .split('\n')
+                .map(|line| line.trim_end_matches('\r'))
+            {
+                if !line.is_empty() {
+                    self.send_split(privmsg.clone(), line).await?;
+                }
+            }
+        }
+
+        self.close_batch(batch).await
+    }
+
+    async fn send_chathistory_targets(&mut self, from: &str, to: &str, limit: usize) -> Result<()> {
+        let (from, to) = match (MsgRef::parse(from), MsgRef::parse(to)) {
+            (Some(MsgRef::Timestamp(from)), Some(MsgRef::Timestamp(to))) => {
+                (from.min(to), from.max(to))
+            }
+            _ => {
+                return self
+                    .send_chathistory_fail("INVALID_PARAMS", &["TARGETS"], "Invalid timestamps")
+                    .await;
+            }
+        };
+
+        let names = self
+            .channels
+            .iter()
+            .map(|(name, _)| ChannelName(name.clone()))
+            .collect::<Vec<_>>();
+
+        let mut targets = Vec::new();
+        for name in names {
+            let Some(uri) = self.ircsky.resolve_channel(&name).await else {
+                continue;
+            };
+            let latest = self.ircsky.channels.get(&uri).and_then(|channel| {
+                channel
+                    .history
+                    .iter()
+                    .rev()
+                    .find(|entry| entry.meta.time_us >= from && entry.meta.time_us <= to)
+                    .map(|entry| entry.meta.clone())
+            });
+            if let Some(latest) = latest {
+                targets.push((name, latest));
+            }
+        }
+
+        targets.sort_by_key(|(_, meta)| meta.time_us);
+        targets.truncate(limit);
+
+        let batch = self.open_batch(&["draft/chathistory-targets"]).await?;
+        for (name, latest) in targets {
+            let mut message = Message::builder("CHATHISTORY");
+            if let Some(ref batch) = batch {
+                message = message.tag("batch", batch);
+            }
+            self.send(
+                message
+                    .param("TARGETS")
+                    .param(name)
+                    .param(format!("timestamp={}", latest.time()))
+                    .build(),
+            )
+            .await?;
+        }
+        self.close_batch(batch).await
+    }
+
+    // only clients with the batch capability get one
+    async fn open_batch(&mut self, params: &[&str]) -> Result<Option<String>> {
+        if !self.cap.has_capability("batch") {
+            return Ok(None);
+        }
+
+        self.next_batch += 1;
+        let batch = format!("ch{}", self.next_batch);
+
+        let message = params.iter().fold(
+            Message::builder("BATCH")
+                .prefix("ircsky", None::<String>, None::<String>)
+                .param(format!("+{batch}")),
+            |message, param| message.param(param),
+        );
+        self.send(message.build()).await?;
+
+        Ok(Some(batch))
+    }
+
+    async fn close_batch(&mut self, batch: Option<String>) -> Result<()> {
+        match batch {
+            Some(batch) => {
+                self.send(
+                    Message::builder("BATCH")
+                        .prefix("ircsky", None::<String>, None::<String>)
+                        .param(format!("-{batch}"))
+                        .build(),
+                )
+                .await
+            }
+            None => Ok(()),
+        }
+    }
+
+    async fn send_chathistory_fail(
+        &mut self,
+        code: &str,
+        context: &[&str],
+        description: &str,
+    ) -> Result<()> {
+        let message = context.iter().fold(
+            Message::builder("FAIL").param("CHATHISTORY").param(code),
+            |message, param| message.param(param),
+        );
+        self.send(message.trailing(description).build()).await
+    }
+}
@@ -1,9 +1,9 @@
 use anyhow::Result;
-use irc_rust::{parsed::Parsed, Message};
+use irc_rust::{builder::Builder, parsed::Parsed, Message};
 use tokio::io::{AsyncRead, AsyncWrite};
 
 use crate::{
-    irc::{IrcClient, UserState},
+    irc::{IrcClient, UserState, WithTags},
     ircsky, psky,
 };
 
@@ -24,7 +24,7 @@ where
             if self
                 .channels
                 .iter()
-                .any(|(name, _)| name == channel_name.0.as_str())
+                .any(|(name, _)| name.eq_ignore_ascii_case(&channel_name.0))
             {
                 continue; // Already in channel
             }
@@ -44,55 +44,66 @@ where
                 }
             };
 
-            let mut channel = self
-                .ircsky
-                .channels
-                .get_mut(&channel_uri)
-                .ok_or(anyhow::anyhow!(
-                    "resolve_channel should've inserted the channel",
-                ))?;
-
-            let has_topic = channel.room.topic.is_some();
-
-            self.channels.push((
-                channel_name.0.clone(),
-                tokio_stream::wrappers::BroadcastStream::new(channel.sender.subscribe()),
-            ));
-
-            match self.user {
+            // get_user can go to the network and touches every channel, so
+            // it has to be done before this one is locked
+            let user = match self.user {
                 UserState::LoggedIn(_, ref did, _) => {
-                    let (user_, _) = self.ircsky.get_user(did).await?;
-                    let user = user_.as_ref().clone();
-                    drop(user_);
+                    let (user, _) = self.ircsky.get_user(did).await?;
+                    Some(user.as_ref().clone())
+                }
+                _ => None,
+            };
+
+            let (channel_name, has_topic) = {
+                let mut channel =
+                    self.ircsky
+                        .channels
+                        .get_mut(&channel_uri)
+                        .ok_or(anyhow::anyhow!(
+                            "resolve_channel should've inserted the channel",
+                        ))?;
 
-                    channel.users.insert(did.to_owned());
+                self.channels.push((
+                    channel.name.0.clone(),
+                    tokio_stream::wrappers::BroadcastStream::new(channel.sender.subscribe()),
+                ));
 
+                if let Some(ref user) = user {
+                    channel.users.insert(user.did.clone());
                     channel
                         .sender
                         .send(psky::PskyEvent::Join(user.clone(), channel.name.clone()))?;
+                }
+
+                // reply with the room's own spelling of the name
+                (channel.name.clone(), channel.room.topic.is_some())
+            };
 
-                    drop(channel);
-                    self.send(
+            // guests have no account and go by their nick
+            let join = match (&self.user, user) {
+                (_, Some(user)) => Some(
+                    self.extended_join(
                         Message::builder("JOIN")
+                            .tags(&self.user_tags(&user))
                             .prefix(&nick, Some(user.did.as_str()), Some("the.atmosphere"))
-                            .param(channel_name.clone())
-                            .build(),
-                    )
-                    .await?;
-                }
-                UserState::LoggedOut(ref nick) => {
-                    drop(channel);
-                    self.send(
+                            .param(channel_name.clone()),
+                        user.account(),
+                        user.realname(),
+                    ),
+                ),
+                (UserState::LoggedOut(_), _) => Some(
+                    self.extended_join(
                         Message::builder("JOIN")
-                            .prefix(nick, Some("logged-out"), Some("the.atmosphere"))
-                            .param(channel_name.clone())
-                            .build(),
-                    )
-                    .await?
-                }
-                _ => {
-                    drop(channel);
-                }
+                            .prefix(&nick, Some("logged-out"), Some("the.atmosphere"))
+                            .param(channel_name.clone()),
+                        None,
+                        &nick,
+                    ),
+                ),
+                _ => None,
+            };
+            if let Some(join) = join {
+                self.send(join.build()).await?;
             }
 
             if has_topic {
@@ -105,4 +116,13 @@ where
         }
         Ok(())
     }
+
+    // JOIN #chan <account|*> :<realname> for extended-join clients
+    pub fn extended_join(&self, join: Builder, account: Option<&str>, realname: &str) -> Builder {
+        if self.cap.has_capability("extended-join") {
+            join.param(account.unwrap_or("*")).trailing(realname)
+        } else {
+            join
+        }
+    }
 }
@@ -1,4 +1,8 @@
+mod authenticate;
+mod away;
+mod batch;
 mod cap;
+mod chathistory;
 mod join;
 mod list;
 mod mode;
@@ -11,5 +15,7 @@ mod pass;
 mod ping;
 mod privmsg;
 mod quit;
+mod stats;
 mod topic;
 mod who;
+mod whois;
@@ -2,7 +2,7 @@ use anyhow::Result;
 use irc_rust::{parsed::Parsed, Message};
 use tokio::io::{AsyncRead, AsyncWrite};
 
-use crate::{irc::IrcClient, ircsky::ChannelName};
+use crate::{irc::IrcClient, ircsky::ChannelName, nick};
 
 impl<T> IrcClient<T>
 where
@@ -48,15 +48,7 @@ where
                 )
                 .await
             }
-        } else if message.param(1).is_some() {
-            self.send(
-                Message::builder("501")
-                    .param(&nick)
-                    .trailing("Unknown MODE flag")
-                    .build(),
-            )
-            .await
-        } else {
+        } else if !nick::eq(mode_of, &nick) {
             self.send(
                 Message::builder("502")
                     .param(&nick)
@@ -64,6 +56,57 @@ where
                     .build(),
             )
             .await
+        } else {
+            self.handle_user_mode(&nick, message.param(1)).await
         }
     }
+
+    // the only user mode is +B, marking the user as a bot
+    async fn handle_user_mode(&mut self, nick: &str, change: Option<&str>) -> Result<()> {
+        let did = self.user.did().cloned();
+
+        let bot = match (change, &did) {
+            (None, _) => {
+                let bot = did
+                    .and_then(|did| self.ircsky.users.get(&did).map(|user| user.bot))
+                    .unwrap_or(false);
+                return self
+                    .send(
+                        Message::builder("221")
+                            .param(nick)
+                            .param(if bot { "+B" } else { "+" })
+                            .build(),
+                    )
+                    .await;
+            }
+            (Some("+B"), Some(_)) => true,
+            (Some("-B"), Some(_)) => false,
+            _ => {
+                return self
+                    .send(
+                        Message::builder("501")
+                            .param(nick)
+                            .trailing("Unknown MODE flag")
+                            .build(),
+                    )
+                    .await;
+            }
+        };
+
+        if let Some(did) = did {
+            self.ircsky.users.alter(&did, |_, mut user| {
+                user.bot = bot;
+                user
+            });
+        }
+
+        self.send(
+            Message::builder("MODE")
+                .prefix(nick, None::<String>, None::<String>)
+                .param(nick)
+                .trailing(if bot { "+B" } else { "-B" })
+                .build(),
+        )
+        .await
+    }
 }
@@ -30,9 +30,7 @@ where
                     let mut ret = Vec::new();
                     for user in &channel.users {
                         if let Some(user_) = self.ircsky.users.get(user) {
-                            if let Some(handle) = user_.handle.as_ref() {
-                                ret.push(handle.to_string());
-                            }
+                            ret.push(user_.nick());
                         }
                     }
                     ret
@@ -74,8 +72,5 @@ where
                 .build(),
         )
         .await
-        .unwrap();
-
-        Ok(())
     }
 }
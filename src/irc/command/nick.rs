@@ -2,12 +2,21 @@ use anyhow::Result;
 use irc_rust::{parsed::Parsed, Message};
 use tokio::io::{AsyncRead, AsyncWrite};
 
-use atrium_api::agent::{store::MemorySessionStore, AtpAgent};
-use atrium_xrpc_client::reqwest::ReqwestClient;
+use atrium_api::agent::{store::SessionStore, AtpAgent};
+use atrium_api::did_doc::DidDocument;
+use atrium_api::types::TryFromUnknown;
+use atrium_api::xrpc::{error::Error, http::StatusCode};
+use atrium_xrpc_client::reqwest::{ReqwestClient, ReqwestClientBuilder};
 use tokio_stream::wrappers::BroadcastStream;
 
 use crate::atproto;
-use crate::irc::{IrcClient, UserState};
+use crate::did::Did;
+use crate::http::Http;
+use crate::irc::{CapState, IrcClient, UserState, WithTags};
+use crate::ircsky::User;
+use crate::nick::{self, NICKLEN};
+use crate::psky::PskyEvent;
+use crate::session::{Session, StoredSession};
 
 impl<T> IrcClient<T>
 where
@@ -19,8 +28,31 @@ where
             .or(message.trailing())
             .ok_or(anyhow::anyhow!("No nickname given with NICK"))?;
 
+        if nick.len() > NICKLEN {
+            return self.send_erroneous_nickname(nick).await;
+        }
+
+        // registration waits for CAP END, so SASL can finish first
+        if !self.registered() && matches!(self.cap, CapState::Negotiating(_)) {
+            self.pending_nick = Some(nick.to_string());
+            return Ok(());
+        }
+
+        self.apply_nick(nick).await
+    }
+
+    pub async fn apply_nick(&mut self, nick: &str) -> Result<()> {
         match &self.user {
             UserState::New => {
+                if let Some((did, session)) = self.sasl_login.take() {
+                    return self.log_in(did, session).await;
+                }
+
+                // guests pick their own nick, logins use the handle's
+                if !nick::is_valid(nick) {
+                    return self.send_erroneous_nickname(nick).await;
+                }
+
                 println!("no PASS, got NICK {nick}, creating LoggedOut user");
 
                 self.user = UserState::LoggedOut(nick.to_string());
@@ -34,45 +66,395 @@ where
                 )
                 .await
             }
+            UserState::Pass(password) if password.eq_ignore_ascii_case("oauth") => {
+                let oauth = self
+                    .ircsky
+                    .oauth
+                    .clone()
+                    .ok_or(anyhow::anyhow!("OAuth login isn't enabled on this server"))?;
+                let did = self.ircsky.resolve_handle(nick).await?;
+                let pds = self.ircsky.get_pds(&did).await?;
+                let pending_login = oauth.authorize(nick, &did, &pds, self.ip).await?;
+
+                self.send(
+                    Message::builder("NOTICE")
+                        .prefix("ircsky", None::<String>, None::<String>)
+                        .param(nick)
+                        .trailing(format!(
+                            "To log in, open {} and approve ircsky",
+                            pending_login.url
+                        ))
+                        .build(),
+                )
+                .await?;
+
+                self.user = UserState::Authorizing;
+                self.pending_login = Some(pending_login);
+                Ok(())
+            }
             UserState::Pass(password) => {
-                println!("PASS: {password}, got NICK {nick}");
+                let password = password.clone();
+                let (did, session) = self.password_session(nick, &password).await?;
+                self.log_in(did, session).await
+            }
+            UserState::Authorizing => Ok(()),
+            UserState::LoggedIn(..) | UserState::LoggedOut(_) => self.change_nick(nick).await,
+        }
+    }
 
-                let (did, auth_server) = atproto::get_did_and_auth_endpoint(nick).await?;
-                let agent = AtpAgent::new(
-                    ReqwestClient::new(&auth_server),
-                    MemorySessionStore::default(),
-                );
-                let result = agent.login(&nick, &password).await?;
+    // logs in with a handle and app password, for PASS and SASL
+    pub async fn password_session(&self, handle: &str, password: &str) -> Result<(Did, Session)> {
+        let did = self.ircsky.resolve_handle(handle).await?;
+        let pds = self.ircsky.get_pds(&did).await?;
+        let store = StoredSession::new(self.ircsky.config.sessions.as_ref(), &did, password)?;
+        let client = ReqwestClientBuilder::new(&pds)
+            .client(self.ircsky.http.client().clone())
+            .build();
+        let agent = AtpAgent::new(client, store.clone());
 
-                println!("{:?}", result);
+        // a session stored for the same password is reused, refreshing it if
+        // it has expired, as long as it's still for the same account
+        let resumed = match store.get_session().await {
+            Some(session) if session.did.as_str() == did.as_str() => {
+                agent.resume_session(session).await.is_ok()
+                    && agent
+                        .get_session()
+                        .await
+                        .is_some_and(|session| session.did.as_str() == did.as_str())
+            }
+            _ => false,
+        };
 
-                if did != result.did.as_str() {
-                    anyhow::bail!("DID mismatch");
-                }
+        if !resumed {
+            store.clear_session().await;
+            password_login(
+                &self.ircsky.http,
+                &agent,
+                &store,
+                handle,
+                password,
+                &did,
+                &pds,
+            )
+            .await?;
+        }
+
+        Ok((did, Session::Password(agent)))
+    }
 
-                let (tx, rx) = tokio::sync::broadcast::channel(16);
+    pub async fn complete_oauth_login(&mut self, (code, iss): (String, String)) -> Result<()> {
+        let pending_login = self
+            .pending_login
+            .take()
+            .ok_or(anyhow::anyhow!("No login pending"))?;
 
-                self.channels
-                    .push(("dm".to_string(), BroadcastStream::new(rx)));
-                self.user = UserState::LoggedIn(nick.to_string(), did.clone(), agent);
+        let session = pending_login.complete(&code, &iss).await?;
+        let did = session.did.clone();
+        self.log_in(did, Session::OAuth(Box::new(session))).await
+    }
 
-                self.ircsky.get_user(&did).await?;
-                self.ircsky.users.alter(&did, |_, mut user| {
-                    user.sender = Some(tx);
-                    user
-                });
+    async fn log_in(&mut self, did: Did, session: Session) -> Result<()> {
+        let (tx, rx) = tokio::sync::broadcast::channel(16);
+
+        self.channels
+            .push(("dm".to_string(), BroadcastStream::new(rx)));
+
+        let (user, _) = self.ircsky.get_user(&did).await?;
+        let nick = user.as_ref().nick();
+        let was_connected = user.as_ref().sender.is_some();
+        drop(user);
+        self.ircsky.users.alter(&did, |_, mut user| {
+            user.sender = Some(tx);
+            user
+        });
+        // no longer away for not being connected
+        if !was_connected {
+            self.ircsky.broadcast_away(&did);
+        }
+
+        self.user = UserState::LoggedIn(nick, did, session);
+
+        self.register_user().await?;
+        self.deliver_queued_dms().await
+    }
+
+    async fn change_nick(&mut self, new: &str) -> Result<()> {
+        let old = self.user.get_nick()?.to_owned();
+        if new == old {
+            return Ok(());
+        }
 
-                self.register_user().await
+        // guests are invisible, only their own client needs to know
+        let did = match self.user.did() {
+            Some(did) => did.to_owned(),
+            None => {
+                if !nick::is_valid(new) {
+                    return self.send_erroneous_nickname(new).await;
+                }
+                self.user.set_nick(new.to_string());
+                return self
+                    .send(
+                        Message::builder("NICK")
+                            .prefix(&old, None::<String>, None::<String>)
+                            .param(new)
+                            .build(),
+                    )
+                    .await;
             }
-            _ => {
-                self.send(
+        };
+
+        let (user, _) = self.ircsky.get_user(&did).await?;
+        let identity_nick = nick::from_identity(user.as_ref().handle.as_deref(), &did);
+        drop(user);
+
+        // going back to the handle's nick is always allowed
+        let irc_nick = if nick::eq(new, &identity_nick) {
+            None
+        } else if !nick::is_valid(new) {
+            return self.send_erroneous_nickname(new).await;
+        } else {
+            Some(new.to_string())
+        };
+
+        let taken = self
+            .ircsky
+            .users
+            .iter()
+            .any(|user| user.did != did && nick::eq(&user.nick(), new));
+        if taken {
+            return self
+                .send(
                     Message::builder("433")
-                        .param(self.user.get_nick()?)
-                        .trailing("Can't change nickname")
+                        .param(&old)
+                        .param(new)
+                        .trailing("Nickname is already in use")
                         .build(),
                 )
-                .await
+                .await;
+        }
+
+        self.ircsky.users.alter(&did, |_, mut user| {
+            user.irc_nick = irc_nick;
+            user
+        });
+        let user = match self.ircsky.users.get(&did) {
+            Some(user) => user.clone(),
+            None => return Ok(()),
+        };
+        let new = user.nick();
+
+        self.user.set_nick(new.clone());
+        self.send_nick_change(&user, &old, &new).await?;
+
+        self.ircsky.broadcast_to_user_channels(&did, |name, names| {
+            PskyEvent::Nick(user.clone(), old.clone(), name, names)
+        });
+
+        Ok(())
+    }
+
+    pub async fn send_nick_change(&mut self, user: &User, old: &str, new: &str) -> Result<()> {
+        self.send(
+            Message::builder("NICK")
+                .tags(&self.user_tags(user))
+                .prefix(old, Some(&user.did), Some("the.atmosphere"))
+                .param(new)
+                .build(),
+        )
+        .await
+    }
+
+    async fn send_erroneous_nickname(&mut self, nick: &str) -> Result<()> {
+        self.send(
+            Message::builder("432")
+                .param(self.user.nick().unwrap_or("*"))
+                .param(nick)
+                .trailing("Erroneous nickname")
+                .build(),
+        )
+        .await
+    }
+}
+
+// logs in at the PDS from the DID document, falling back to its entryway for
+// anything but rejected credentials
+async fn password_login(
+    http: &Http,
+    agent: &AtpAgent<StoredSession, ReqwestClient>,
+    store: &StoredSession,
+    nick: &str,
+    password: &str,
+    did: &str,
+    pds: &str,
+) -> Result<()> {
+    let result = match agent.login(nick, password).await {
+        Ok(result) => result,
+        Err(Error::XrpcResponse(e))
+            if e.status == StatusCode::UNAUTHORIZED
+                || e.status == StatusCode::TOO_MANY_REQUESTS =>
+        {
+            return Err(Error::XrpcResponse(e).into());
+        }
+        Err(e) => {
+            let entryway = atproto::get_auth_endpoint(http, pds).await?;
+            if entryway.trim_end_matches('/') == pds.trim_end_matches('/') {
+                return Err(e.into());
             }
+            agent.configure_endpoint(entryway);
+            agent.login(nick, password).await?
         }
+    };
+
+    // the session was stored by the login already, it mustn't be resumed later
+    if did != result.did.as_str() {
+        store.clear_session().await;
+        anyhow::bail!("DID mismatch");
+    }
+
+    // the agent switches to the PDS in the returned DID document, which has to
+    // be the account's own
+    let did_doc = result
+        .did_doc
+        .as_ref()
+        .and_then(|did_doc| DidDocument::try_from_unknown(did_doc.clone()).ok());
+    if did_doc.is_some_and(|did_doc| did_doc.id != did) {
+        store.clear_session().await;
+        anyhow::bail!("DID document mismatch");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use http_body_util::Full;
+    use hyper::{server::conn::http1, service::service_fn, Response};
+    use hyper_util::rt::TokioIo;
+    use std::convert::Infallible;
+
+    use crate::config::AtprotoSettings;
+
+    const DID: &str = "did:plc:alice";
+
+    // a PDS answering every request with respond(path), returns its URL
+    async fn serve<F>(respond: F) -> String
+    where
+        F: Fn(&str) -> (u16, String) + Clone + Send + Sync + 'static,
+    {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let respond = respond.clone();
+                let service = service_fn(move |request: hyper::Request<_>| {
+                    let (status, body) = respond(request.uri().path());
+                    let mut response = Response::new(Full::new(Bytes::from(body)));
+                    *response.status_mut() = status.try_into().unwrap();
+                    response.headers_mut().insert(
+                        hyper::header::CONTENT_TYPE,
+                        "application/json".parse().unwrap(),
+                    );
+                    async move { Ok::<_, Infallible>(response) }
+                });
+                tokio::spawn(http1::Builder::new().serve_connection(TokioIo::new(stream), service));
+            }
+        });
+        url
+    }
+
+    fn session(did: &str) -> (u16, String) {
+        let session = serde_json::json!({
+            "accessJwt": "access",
+            "refreshJwt": "refresh",
+            "handle": "alice.test",
+            "did": did,
+        });
+        (200, session.to_string())
+    }
+
+    fn error(status: u16) -> (u16, String) {
+        (status, r#"{"error":"InternalServerError"}"#.to_string())
+    }
+
+    async fn login(pds: &str) -> Result<()> {
+        login_with(pds, &StoredSession::new(None, DID, "password")?).await
+    }
+
+    async fn login_with(pds: &str, store: &StoredSession) -> Result<()> {
+        let http = Http::new(&AtprotoSettings {
+            retries: 0,
+            ..Default::default()
+        })?;
+        let client = ReqwestClientBuilder::new(pds)
+            .client(http.client().clone())
+            .build();
+        let agent = AtpAgent::new(client, store.clone());
+        password_login(&http, &agent, store, "alice.test", "password", DID, pds).await
+    }
+
+    #[tokio::test]
+    async fn logs_in_at_the_pds() {
+        let pds = serve(|path| match path {
+            "/xrpc/com.atproto.server.createSession" => session(DID),
+            _ => error(404),
+        })
+        .await;
+
+        login(&pds).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_the_entryway() {
+        let entryway = serve(|path| match path {
+            "/xrpc/com.atproto.server.createSession" => session(DID),
+            _ => error(404),
+        })
+        .await;
+        let pds = serve(move |path| match path {
+            "/.well-known/oauth-protected-resource" => (
+                200,
+                serde_json::json!({ "authorization_servers": [entryway] }).to_string(),
+            ),
+            _ => error(500),
+        })
+        .await;
+
+        login(&pds).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn rejected_credentials_skip_the_entryway() {
+        let entryway = serve(|path| match path {
+            "/xrpc/com.atproto.server.createSession" => session(DID),
+            _ => error(404),
+        })
+        .await;
+        let pds = serve(move |path| match path {
+            "/.well-known/oauth-protected-resource" => (
+                200,
+                serde_json::json!({ "authorization_servers": [entryway] }).to_string(),
+            ),
+            _ => error(401),
+        })
+        .await;
+
+        assert!(login(&pds).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_a_session_for_another_did() {
+        let pds = serve(|path| match path {
+            "/xrpc/com.atproto.server.createSession" => session("did:plc:mallory"),
+            _ => error(404),
+        })
+        .await;
+
+        let store = StoredSession::new(None, DID, "password").unwrap();
+        let e = login_with(&pds, &store).await.unwrap_err();
+        assert_eq!(e.to_string(), "DID mismatch");
+        assert!(store.get_session().await.is_none());
     }
 }
@@ -35,17 +35,12 @@ where
             }
         };
 
-        dbg!(&channel_name);
-        dbg!(&self.channels);
-
-        match self
+        let channel_name = match self
             .channels
             .iter()
-            .position(|(name, _)| name == channel_name.0.as_str())
+            .position(|(name, _)| name.eq_ignore_ascii_case(&channel_name.0))
         {
-            Some(idx) => {
-                self.channels.remove(idx);
-            }
+            Some(idx) => ircsky::ChannelName(self.channels.remove(idx).0),
             None => {
                 return self
                     .send(
@@ -57,7 +52,7 @@ where
                     )
                     .await;
             }
-        }
+        };
 
         match self.user {
             UserState::LoggedIn(_, ref did, _) => {
@@ -69,7 +64,7 @@ where
                     channel.users.remove(did);
                     _ = channel
                         .sender
-                        .send(psky::PskyEvent::Part(user.clone(), channel_name.to_owned()));
+                        .send(psky::PskyEvent::Part(user.clone(), channel.name.clone()));
                     channel
                 });
 
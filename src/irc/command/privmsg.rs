@@ -6,7 +6,7 @@ use irc_rust::{parsed::Parsed, Message};
 use tokio::io::{AsyncRead, AsyncWrite};
 
 use crate::{
-    atproto,
+    aturi::AtUri,
     irc::{IrcClient, UserState},
     ircsky, psky,
 };
@@ -47,6 +47,13 @@ where
             msg_line = message.trailing().unwrap();
         }
 
+        if let Some(batch) = message.tag("batch") {
+            let concat = message.tag("draft/multiline-concat").is_some();
+            return self
+                .add_to_multiline(batch, &recipient, msg_line, concat)
+                .await;
+        }
+
         if msg_line.is_empty() {
             return self
                 .send(
@@ -59,101 +66,163 @@ where
                 .await;
         }
 
-        if !recipient.starts_with("#") {
-            let did = atproto::resolve_handle(recipient.as_str()).await?; // TODO: cache !!!
-            let (user_, _) = self.ircsky.get_user(&did).await?;
-            let user = user_.as_ref();
+        self.privmsg(recipient, msg_line).await
+    }
 
-            let (sender_, _) = self
-                .ircsky
-                .get_user(&self.user.did().ok_or(anyhow::anyhow!("no self did"))?)
-                .await?;
-            let sender = sender_.as_ref().clone();
-            drop(sender_);
-
-            user.sender
-                .as_ref()
-                .ok_or(anyhow::anyhow!("User has no sender"))?
-                .send(psky::PskyEvent::PrivateMessage(
-                    sender.clone(),
-                    psky::Message {
-                        r#type: "social.psky.chat.message".to_string(),
-                        content: msg_line.to_string(),
-                        room: ircsky::ChannelUri(recipient.clone()),
-                    },
-                    ircsky::ChannelName(recipient.clone()),
-                ))?;
-            drop(user_);
-
-            if self.cap.has_capability("echo-message") {
-                self.send(
-                    Message::builder("PRIVMSG")
-                        .prefix(
-                            sender
-                                .handle
-                                .ok_or(anyhow::anyhow!("no self handle in pm"))?,
-                            Some(sender.did.clone()),
-                            Some("the.atosphere"),
-                        )
-                        .param(&recipient)
-                        .trailing(&msg_line)
-                        .build(),
-                )
-                .await?;
-            }
+    pub async fn privmsg(&mut self, recipient: String, msg_line: &str) -> Result<()> {
+        let nick = self.user.get_nick()?.to_owned();
 
-            return Ok(());
+        if !recipient.starts_with("#") {
+            return self.direct_message(&recipient, msg_line).await;
         }
 
         let channel_name = ircsky::ChannelName(recipient);
 
+        // guests can't publish, and channels are +n, so only members can
         let resolved = match self.ircsky.resolve_channel(&channel_name).await {
-            Some(resolved) => resolved,
+            Some(resolved) if matches!(self.user, UserState::LoggedIn(..)) => resolved,
+            _ => return self.send_cannot_send(&nick, &channel_name).await,
+        };
+        let joined = self.ircsky.channels.get(&resolved).is_some_and(|channel| {
+            self.channels
+                .iter()
+                .any(|(name, _)| name.eq_ignore_ascii_case(&channel.name.0))
+        });
+        if !joined {
+            return self.send_cannot_send(&nick, &channel_name).await;
+        }
+
+        if !self.try_publish() {
+            return self.send_publish_throttled(&channel_name.0).await;
+        }
+
+        self.create_record(
+            "social.psky.chat.message",
+            psky::Message {
+                r#type: "social.psky.chat.message".to_string(),
+                room: resolved.at_uri(),
+                content: msg_line.to_string(),
+                //  facets: None,
+            },
+        )
+        .await
+    }
+
+    async fn send_cannot_send(&mut self, nick: &str, channel: &ircsky::ChannelName) -> Result<()> {
+        self.send(
+            Message::builder("404")
+                .param(nick)
+                .param(channel)
+                .trailing("Cannot send to channel")
+                .build(),
+        )
+        .await
+    }
+
+    // delivered straight to the recipient's IRC sessions, or published as a
+    // record when they have none and a DM collection is configured
+    async fn direct_message(&mut self, recipient: &str, msg_line: &str) -> Result<()> {
+        let nick = self.user.get_nick()?.to_owned();
+
+        let from = match self.user.did() {
+            Some(did) => did.to_owned(),
             None => {
                 return self
-                    .send(
-                        Message::builder("404")
-                            .param(&nick)
-                            .param(&channel_name)
-                            .trailing("Cannot send to channel")
-                            .build(),
-                    )
+                    .send_server_notice("Guests can't send direct messages, log in with PASS")
                     .await;
             }
         };
 
-        if let UserState::LoggedIn(_, ref did, ref agent) = self.user {
-            let record = atrium_api::com::atproto::repo::create_record::InputData {
-                collection: atrium_api::types::string::Nsid::from_str("social.psky.chat.message")
-                    .map_err(|e| anyhow::anyhow!(e))?,
-                record: psky::Message {
-                    r#type: "social.psky.chat.message".to_string(),
-                    room: resolved,
-                    content: msg_line.to_string(),
-                    //  facets: None,
-                }
-                .try_into_unknown()?,
-                repo: atrium_api::types::string::Did::from_str(did)
-                    .map_err(|e| anyhow::anyhow!(e))?
-                    .into(),
-                rkey: None,
-                swap_commit: None,
-                validate: Some(false),
-            };
-
-            agent
-                .api
-                .com
-                .atproto
-                .repo
-                .create_record(record.into())
+        let to = match self.ircsky.resolve_nick(recipient).await {
+            Ok(to) => to,
+            Err(_) => return self.send_no_such_nick(recipient).await,
+        };
+        let (to_user, from_user) = match (
+            self.ircsky
+                .get_user(&to)
+                .await
+                .map(|(user, _)| user.as_ref().clone()),
+            self.ircsky
+                .get_user(&from)
+                .await
+                .map(|(user, _)| user.as_ref().clone()),
+        ) {
+            (Ok(to_user), Ok(from_user)) => (to_user, from_user),
+            _ => return self.send_no_such_nick(recipient).await,
+        };
+
+        let meta = psky::MessageMeta::now();
+        if !self
+            .ircsky
+            .deliver_dm(from_user.clone(), &to, msg_line.to_string(), meta.clone())
+        {
+            // kept for their next IRC login, and published for psky if configured
+            let queued = self.ircsky.dm_queue.push(
+                &to,
+                from_user.clone(),
+                msg_line.to_string(),
+                meta,
+                self.ircsky.config.irc.dm_queue,
+                self.ircsky.config.irc.dm_queue_recipients,
+            );
+            if queued {
+                self.send_server_notice(&format!(
+                    "{recipient} isn't connected over IRC, your message was queued for their next login"
+                ))
                 .await?;
-        } else {
+            }
+
+            match self.ircsky.config.psky.dm_collection.clone() {
+                Some(collection) => {
+                    if !self.try_publish() {
+                        return self.send_publish_throttled(recipient).await;
+                    }
+
+                    let record = psky::DirectMessage {
+                        r#type: collection.clone(),
+                        content: msg_line.to_string(),
+                        recipient: to.clone(),
+                    };
+                    if self.create_record(&collection, record).await.is_err() {
+                        return self
+                            .send_server_notice(&format!(
+                                "Your message to {recipient} couldn't be published"
+                            ))
+                            .await;
+                    }
+                }
+                None if !queued => {
+                    return self
+                        .send_server_notice(&format!(
+                            "{recipient} isn't connected over IRC, your message wasn't delivered"
+                        ))
+                        .await;
+                }
+                None => {}
+            }
+        }
+
+        if let Some(away) = to_user.away_message() {
             self.send(
-                Message::builder("404")
+                Message::builder("301")
                     .param(&nick)
-                    .param(channel_name)
-                    .trailing("Cannot send to channel")
+                    .param(recipient)
+                    .trailing(away)
+                    .build(),
+            )
+            .await?;
+        }
+
+        if self.cap.has_capability("echo-message") {
+            self.send(
+                Message::builder("PRIVMSG")
+                    .prefix(
+                        from_user.nick(),
+                        Some(from_user.did.clone()),
+                        Some("the.atmosphere"),
+                    )
+                    .param(recipient)
+                    .trailing(msg_line)
                     .build(),
             )
             .await?;
@@ -161,4 +230,123 @@ where
 
         Ok(())
     }
+
+    // DMs queued while we were offline, with their original time
+    pub async fn deliver_queued_dms(&mut self) -> Result<()> {
+        let did = match self.user.did() {
+            Some(did) => did.to_owned(),
+            None => return Ok(()),
+        };
+        let nick = self.user.get_nick()?.to_owned();
+
+        self.ircsky.dm_queue.register(&did);
+        let expiry = self.ircsky.config.irc.dm_queue_expiry();
+        for dm in self.ircsky.dm_queue.take(&did, expiry) {
+            self.handle_event(psky::PskyEvent::PrivateMessage(
+                dm.from,
+                psky::Message {
+                    r#type: "social.psky.chat.message".to_string(),
+                    content: dm.content,
+                    room: AtUri::repo(did.clone()),
+                },
+                ircsky::ChannelName(nick.clone()),
+                dm.meta,
+            ))
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    async fn create_record(
+        &mut self,
+        collection: &str,
+        record: impl serde::Serialize,
+    ) -> Result<()> {
+        let (did, session) = match self.user {
+            UserState::LoggedIn(_, ref did, ref mut session) => (did, session),
+            _ => anyhow::bail!("Not logged in"),
+        };
+
+        let record = atrium_api::com::atproto::repo::create_record::InputData {
+            collection: atrium_api::types::string::Nsid::from_str(collection)
+                .map_err(|e| anyhow::anyhow!(e))?,
+            record: record.try_into_unknown()?,
+            repo: atrium_api::types::string::Did::from_str(did)
+                .map_err(|e| anyhow::anyhow!(e))?
+                .into(),
+            rkey: None,
+            swap_commit: None,
+            validate: Some(false),
+        };
+
+        session.create_record(record).await
+    }
+
+    async fn send_no_such_nick(&mut self, target: &str) -> Result<()> {
+        let nick = self.user.get_nick()?.to_owned();
+
+        self.send(
+            Message::builder("401")
+                .param(&nick)
+                .param(target)
+                .trailing("No such nick")
+                .build(),
+        )
+        .await
+    }
+
+    async fn send_server_notice(&mut self, text: &str) -> Result<()> {
+        let nick = self.user.get_nick()?.to_owned();
+
+        self.send(
+            Message::builder("NOTICE")
+                .prefix("ircsky", None::<String>, None::<String>)
+                .param(nick)
+                .trailing(text)
+                .build(),
+        )
+        .await
+    }
+
+    // record writes are limited per connection and per DID, on top of fakelag
+    fn try_publish(&mut self) -> bool {
+        if !self.publish.try_take() {
+            return false;
+        }
+
+        match self.user.did() {
+            Some(did) => self
+                .ircsky
+                .rate_limits
+                .try_publish(did, &self.ircsky.config.irc.flood),
+            None => true,
+        }
+    }
+
+    async fn send_publish_throttled(&mut self, target: &str) -> Result<()> {
+        let nick = self.user.get_nick()?.to_owned();
+        let description = "You're sending messages too fast, message not published";
+
+        if self.cap.has_capability("standard-replies") {
+            self.send(
+                Message::builder("FAIL")
+                    .param("PRIVMSG")
+                    .param("RATE_LIMITED")
+                    .param(target)
+                    .trailing(description)
+                    .build(),
+            )
+            .await
+        } else {
+            self.send(
+                Message::builder("NOTICE")
+                    .prefix("ircsky", None::<String>, None::<String>)
+                    .param(nick)
+                    .trailing(format!("{target}: {description}"))
+                    .build(),
+            )
+            .await
+        }
+    }
 }
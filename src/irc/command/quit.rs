@@ -8,9 +8,11 @@ impl<T> IrcClient<T>
 where
     T: AsyncRead + AsyncWrite,
 {
-    pub async fn handle_quit(&mut self, _message: Parsed<'_>) -> Result<()> {
-        // TODO: send PART messages if was LoggedIn
-        self.stop().await;
+    pub async fn handle_quit(&mut self, message: Parsed<'_>) -> Result<()> {
+        match message.trailing().or(message.param(0)) {
+            Some(reason) => self.quit(format!("Quit: {reason}")),
+            None => self.quit("Quit"),
+        }
         Ok(())
     }
 }
@@ -0,0 +1,82 @@
+use std::sync::atomic::Ordering;
+
+use anyhow::Result;
+use irc_rust::{parsed::Parsed, Message};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::irc::IrcClient;
+
+impl<T> IrcClient<T>
+where
+    T: AsyncRead + AsyncWrite,
+{
+    pub async fn handle_stats(&mut self, message: Parsed<'_>) -> Result<()> {
+        let nick = self.user.get_nick()?.to_owned();
+
+        let query = match message.param(0).or(message.trailing()) {
+            Some(query) => query,
+            None => {
+                return self
+                    .send(
+                        Message::builder("461")
+                            .param(&nick)
+                            .param("STATS")
+                            .trailing("Not enough parameters")
+                            .build(),
+                    )
+                    .await;
+            }
+        };
+
+        if query == "q" {
+            let (lines, bytes) = self.sendq.depth();
+            self.send(
+                Message::builder("249")
+                    .param(&nick)
+                    .param(query)
+                    .trailing(format!(
+                        "your sendq: {} lines, {} bytes (limit {})",
+                        lines, bytes, self.ircsky.config.irc.sendq
+                    ))
+                    .build(),
+            )
+            .await?;
+
+            // guests only get to see their own
+            if self.user.did().is_none() {
+                return self.send_end_of_stats(&nick, query).await;
+            }
+
+            let metrics = &self.ircsky.metrics;
+            let server = format!(
+                "server sendq: {} lines, {} bytes across {} clients, peak {} bytes, {} exceeded",
+                metrics.sendq_lines.load(Ordering::Relaxed),
+                metrics.sendq_bytes.load(Ordering::Relaxed),
+                metrics.clients.load(Ordering::Relaxed),
+                metrics.sendq_peak.load(Ordering::Relaxed),
+                metrics.sendq_exceeded.load(Ordering::Relaxed),
+            );
+            self.send(
+                Message::builder("249")
+                    .param(&nick)
+                    .param(query)
+                    .trailing(server)
+                    .build(),
+            )
+            .await?;
+        }
+
+        self.send_end_of_stats(&nick, query).await
+    }
+
+    async fn send_end_of_stats(&mut self, nick: &str, query: &str) -> Result<()> {
+        self.send(
+            Message::builder("219")
+                .param(nick)
+                .param(query)
+                .trailing("End of /STATS report")
+                .build(),
+        )
+        .await
+    }
+}
@@ -3,11 +3,58 @@ use irc_rust::{parsed::Parsed, Message};
 use tokio::io::{AsyncRead, AsyncWrite};
 
 use crate::{
-    atproto,
     irc::IrcClient,
     ircsky::{ChannelName, User},
 };
 
+// WHOX fields, in the order they're sent back in
+const WHOX_FIELDS: &str = "tcuihsnfdlaor";
+
+// case-insensitive glob with * and ?, backtracking only to the last * seen
+fn mask_matches(mask: &[u8], s: &[u8]) -> bool {
+    let (mut m, mut i) = (0, 0);
+    let mut star = None;
+
+    while i < s.len() {
+        if m < mask.len() && mask[m] == b'*' {
+            star = Some((m, i));
+            m += 1;
+        } else if m < mask.len() && (mask[m] == b'?' || mask[m].eq_ignore_ascii_case(&s[i])) {
+            m += 1;
+            i += 1;
+        } else if let Some((star_m, star_i)) = star {
+            // let the * take one more byte
+            m = star_m + 1;
+            i = star_i + 1;
+            star = Some((star_m, i));
+        } else {
+            return false;
+        }
+    }
+
+    mask[m..].iter().all(|&c| c == b'*')
+}
+
+struct Whox {
+    fields: String,
+    token: Option<String>,
+}
+
+impl Whox {
+    // %<fields>[,<token>]
+    fn parse(param: Option<&str>) -> Option<Whox> {
+        let spec = param?.strip_prefix('%')?;
+        let (fields, token) = match spec.split_once(',') {
+            Some((fields, token)) => (fields, Some(token.to_string())),
+            None => (spec, None),
+        };
+        Some(Whox {
+            fields: fields.to_string(),
+            token,
+        })
+    }
+}
+
 impl<T> IrcClient<T>
 where
     T: AsyncRead + AsyncWrite,
@@ -17,98 +64,128 @@ where
             .param(0)
             .ok_or(anyhow::anyhow!("No first argument given with WHO"))?;
         let nick = self.user.get_nick()?.to_owned();
+        let whox = Whox::parse(message.param(1).or(message.trailing()));
 
-        if mask.starts_with('#') {
-            // channel
-            let channel_name = ChannelName(mask.to_string());
-            let channel_uri = match self.ircsky.resolve_channel(&channel_name).await {
-                Some(channel) => channel,
-                None => {
-                    return self
-                        .send(
-                            Message::builder("403")
-                                .param(&nick)
-                                .param(&channel_name.0)
-                                .trailing("No such channel")
-                                .build(),
-                        )
-                        .await;
-                }
+        let (channel, users) = if mask.starts_with('#') {
+            let users = match self
+                .ircsky
+                .resolve_channel(&ChannelName(mask.to_string()))
+                .await
+            {
+                Some(uri) => self
+                    .ircsky
+                    .channels
+                    .get(&uri)
+                    .map(|channel| {
+                        channel
+                            .users
+                            .iter()
+                            .filter_map(|did| self.ircsky.users.get(did))
+                            .map(|user| user.value().clone())
+                            .collect::<Vec<_>>()
+                    })
+                    .unwrap_or_default(),
+                None => Vec::new(),
             };
-
-            let channel = self.ircsky.channels.get(&channel_uri);
-            if channel.is_none() {
-                drop(channel);
-                return self
-                    .send(
-                        Message::builder("403")
-                            .param(&nick)
-                            .param(&channel_name.0)
-                            .trailing("No such channel")
-                            .build(),
-                    )
-                    .await;
-            }
-            let channel = channel.unwrap();
-            let users = channel
-                .users
-                .iter()
-                .filter_map(|did| self.ircsky.users.get(did))
-                .map(|user| user.value().clone())
-                .collect::<Vec<_>>();
-            drop(channel);
-            for user in users {
-                self.send_who(&nick, mask, user).await?;
-            }
+            (Some(mask), users)
         } else {
-            // user
-            let did = atproto::resolve_handle(mask).await?.to_string();
-            let user = self.ircsky.get_user(&did).await?.0.as_ref().to_owned();
+            (None, self.who_users(mask).await)
+        };
 
-            self.send_who(&nick, "*", user).await?;
-            self.send_endofwho(&nick, mask).await?;
+        for user in users {
+            self.send_who(&nick, channel, &whox, user).await?;
         }
 
-        Ok(())
+        self.send_endofwho(&nick, mask).await
     }
 
-    async fn send_who(&mut self, nick: &str, mask: &str, user: User) -> Result<()> {
-        let handle = match user.handle {
-            Some(handle) => handle,
+    // users matching a nick mask, from the cache or by resolving it as a nick
+    async fn who_users(&self, mask: &str) -> Vec<User> {
+        let users = self
+            .ircsky
+            .users
+            .iter()
+            .filter(|user| {
+                mask_matches(mask.as_bytes(), user.nick().as_bytes())
+                    || mask_matches(mask.as_bytes(), user.did.as_bytes())
+            })
+            .map(|user| user.value().clone())
+            .collect::<Vec<_>>();
+
+        if !users.is_empty() || mask.contains(['*', '?']) {
+            return users;
+        }
+
+        let did = match self.ircsky.resolve_nick(mask).await {
+            Ok(did) => did,
+            Err(_) => return users,
+        };
+        match self.ircsky.get_user(&did).await {
+            Ok((user, _)) => vec![user.as_ref().clone()],
+            Err(_) => users,
+        }
+    }
+
+    async fn send_who(
+        &mut self,
+        nick: &str,
+        channel: Option<&str>,
+        whox: &Option<Whox>,
+        user: User,
+    ) -> Result<()> {
+        let user_nick = user.nick();
+        let realname = user.realname().to_string();
+        let mut flags = match user.away_message() {
+            Some(_) => "G".to_string(),
+            None => "H".to_string(),
+        };
+        if user.bot {
+            flags.push('B');
+        }
+
+        let whox = match whox {
+            Some(whox) => whox,
             None => {
                 return self
                     .send(
-                        Message::builder("401")
+                        Message::builder("352")
                             .param(nick)
-                            .param(mask)
-                            .trailing("No such user")
+                            .param(channel.unwrap_or("*"))
+                            .param(&user.did)
+                            .param("the.atmosphere")
+                            .param("ircsky")
+                            .param(&user_nick)
+                            .param(&flags)
+                            .trailing(format!("0 {}", realname))
                             .build(),
                     )
                     .await;
             }
         };
 
-        let realname = match user.profile {
-            Some(profile) => match profile.nickname {
-                Some(nickname) => nickname,
-                None => handle.clone(),
-            },
-            None => handle.clone(),
-        };
+        let mut reply = Message::builder("354").param(nick);
+        let mut realname = Some(realname);
+        for field in WHOX_FIELDS.chars().filter(|f| whox.fields.contains(*f)) {
+            reply = match field {
+                't' => reply.param(whox.token.as_deref().unwrap_or("0")),
+                'c' => reply.param(channel.unwrap_or("*")),
+                'u' => reply.param(&user.did),
+                'i' => reply.param("255.255.255.255"),
+                'h' => reply.param("the.atmosphere"),
+                's' => reply.param("ircsky"),
+                'n' => reply.param(&user_nick),
+                'f' => reply.param(&flags),
+                'd' => reply.param("0"),
+                'l' => reply.param("0"),
+                'a' => reply.param(user.account().unwrap_or("0")),
+                'o' => reply.param("0"),
+                // realname is always last, and may contain spaces
+                'r' => reply.trailing(realname.take().unwrap_or_default()),
+                _ => reply,
+            };
+        }
 
-        self.send(
-            Message::builder("352")
-                .param(nick)
-                .param(mask)
-                .param(user.did)
-                .param("the.atmosphere")
-                .param("ircsky")
-                .param(handle)
-                .param("H")
-                .trailing(format!("0 {}", realname))
-                .build(),
-        )
-        .await
+        self.send(reply.build()).await
     }
 
     async fn send_endofwho(&mut self, nick: &str, mask: &str) -> Result<()> {
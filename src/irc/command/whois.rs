@@ -0,0 +1,165 @@
+use anyhow::Result;
+use irc_rust::{parsed::Parsed, Message};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::irc::IrcClient;
+
+impl<T> IrcClient<T>
+where
+    T: AsyncRead + AsyncWrite,
+{
+    pub async fn handle_whois(&mut self, message: Parsed<'_>) -> Result<()> {
+        let nick = self.user.get_nick()?.to_owned();
+
+        // WHOIS [<server>] <nick>
+        let target = match message.param(1).or(message.param(0)).or(message.trailing()) {
+            Some(target) => target.split(',').next().unwrap_or(target),
+            None => {
+                return self
+                    .send(
+                        Message::builder("431")
+                            .param(&nick)
+                            .trailing("No nickname given")
+                            .build(),
+                    )
+                    .await;
+            }
+        };
+
+        let user = match self.ircsky.resolve_nick(target).await {
+            Ok(did) => match self.ircsky.get_user(&did).await {
+                Ok((user, _)) => Some(user.as_ref().clone()),
+                Err(_) => None,
+            },
+            Err(_) => None,
+        };
+
+        let user = match user {
+            Some(user) => user,
+            None => {
+                self.send(
+                    Message::builder("401")
+                        .param(&nick)
+                        .param(target)
+                        .trailing("No such nick")
+                        .build(),
+                )
+                .await?;
+                return self.send_endofwhois(&nick, target).await;
+            }
+        };
+
+        let shown = &user.nick();
+        let realname = user.realname().to_string();
+
+        self.send(
+            Message::builder("311")
+                .param(&nick)
+                .param(shown)
+                .param(&user.did)
+                .param("the.atmosphere")
+                .param("*")
+                .trailing(realname)
+                .build(),
+        )
+        .await?;
+
+        let rooms = self
+            .ircsky
+            .channels
+            .iter()
+            .filter(|channel| channel.users.contains(&user.did))
+            .map(|channel| channel.name.0.clone())
+            .collect::<Vec<_>>();
+        if !rooms.is_empty() {
+            self.send(
+                Message::builder("319")
+                    .param(&nick)
+                    .param(shown)
+                    .trailing(rooms.join(" "))
+                    .build(),
+            )
+            .await?;
+        }
+
+        self.send(
+            Message::builder("312")
+                .param(&nick)
+                .param(shown)
+                .param("ircsky")
+                .trailing("the atmosphere")
+                .build(),
+        )
+        .await?;
+
+        if let Some(away) = user.away_message() {
+            self.send(
+                Message::builder("301")
+                    .param(&nick)
+                    .param(shown)
+                    .trailing(away)
+                    .build(),
+            )
+            .await?;
+        }
+
+        if user.bot {
+            self.send(
+                Message::builder("335")
+                    .param(&nick)
+                    .param(shown)
+                    .trailing("is a bot")
+                    .build(),
+            )
+            .await?;
+        }
+
+        let mut details = vec![
+            format!("did {}", user.did),
+            format!("pds {}", user.pds),
+            match user.handle {
+                Some(ref handle) => format!("handle {handle} is verified"),
+                None => "handle could not be verified".to_string(),
+            },
+        ];
+        if let Some(nickname) = user.profile.as_ref().and_then(|p| p.nickname.as_ref()) {
+            details.push(format!("profile nickname {nickname}"));
+        }
+
+        for detail in details {
+            self.send(
+                Message::builder("320")
+                    .param(&nick)
+                    .param(shown)
+                    .trailing(detail)
+                    .build(),
+            )
+            .await?;
+        }
+
+        if let Some(account) = user.account() {
+            self.send(
+                Message::builder("330")
+                    .param(&nick)
+                    .param(shown)
+                    .param(account)
+                    .trailing("is logged in as")
+                    .build(),
+            )
+            .await?;
+        }
+
+        self.send_endofwhois(&nick, shown).await
+    }
+
+    async fn send_endofwhois(&mut self, nick: &str, target: &str) -> Result<()> {
+        self.send(
+            Message::builder("318")
+                .param(nick)
+                .param(target)
+                .trailing("End of /WHOIS list")
+                .build(),
+        )
+        .await
+    }
+}
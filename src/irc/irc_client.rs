@@ -1,20 +1,28 @@
 use anyhow::Result;
 use irc_rust::Message;
+use std::net::IpAddr;
+use std::sync::atomic::Ordering;
 use tokio::io::{
-    AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, ReadHalf, WriteHalf,
+    AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, BufReader, ReadHalf,
 };
+use tokio::time::Instant;
 use tokio_stream::{StreamExt, StreamMap};
 
-use atrium_api::agent::{store::MemorySessionStore, AtpAgent};
-use atrium_xrpc_client::reqwest::ReqwestClient;
-
+use crate::did::Did;
+use crate::irc::{normalize_tags, SendQueue, WithTags};
+use crate::ircsky::ChannelName;
+use crate::oauth::PendingLogin;
 use crate::psky::PskyEvent;
+use crate::ratelimit::TokenBucket;
+use crate::session::Session;
 use crate::Ircsky;
 
 pub enum UserState {
     New,
     Pass(String),
-    LoggedIn(String, String, AtpAgent<MemorySessionStore, ReqwestClient>),
+    // waiting for the user to approve an OAuth login, see pending_login
+    Authorizing,
+    LoggedIn(String, Did, Session),
     LoggedOut(String),
 }
 
@@ -31,9 +39,16 @@ impl UserState {
         self.nick().ok_or(anyhow::anyhow!("No nickname"))
     }
 
-    pub fn did(&self) -> Option<&str> {
+    pub fn set_nick(&mut self, new: String) {
+        match self {
+            UserState::LoggedIn(nick, _, _) | UserState::LoggedOut(nick) => *nick = new,
+            _ => {}
+        }
+    }
+
+    pub fn did(&self) -> Option<&Did> {
         match self {
-            UserState::LoggedIn(_, did, _) => Some(did.as_str()),
+            UserState::LoggedIn(_, did, _) => Some(did),
             _ => None,
         }
     }
@@ -74,6 +89,15 @@ impl CapState {
     }
 }
 
+// a draft/multiline batch the client has opened but not yet closed
+pub struct MultilineBatch {
+    pub reference: String,
+    pub target: String,
+    pub content: String,
+    pub lines: usize,
+    pub failed: bool,
+}
+
 pub struct IrcClient<T>
 where
     T: AsyncRead + AsyncWrite,
@@ -81,50 +105,124 @@ where
     pub user: UserState,
     pub cap: CapState,
     read: BufReader<ReadHalf<T>>,
-    write: WriteHalf<T>,
+    pub sendq: SendQueue,
     pub ircsky: Ircsky,
     line_buffer: Vec<u8>,
+    overlong: bool,
     empty_lines: usize,
+    pub next_batch: u64,
+    pub multiline: Option<MultilineBatch>,
+    connected_at: Instant,
+    last_activity: Instant,
+    ping_sent: Option<Instant>,
+    quit_reason: Option<String>,
+    commands: TokenBucket,
+    pub publish: TokenBucket,
+    lagged_until: Instant,
+    pub pending_login: Option<PendingLogin>,
+    // the SASL payload received so far, and the login it ended in
+    pub sasl: Option<String>,
+    pub sasl_login: Option<(Did, Session)>,
+    // a NICK sent during CAP negotiation, applied at CAP END
+    pub pending_nick: Option<String>,
+    pub ip: IpAddr,
     pub channels: Vec<(String, tokio_stream::wrappers::BroadcastStream<PskyEvent>)>,
 }
 
 impl<T> IrcClient<T>
 where
-    T: AsyncRead + AsyncWrite,
+    T: AsyncRead + AsyncWrite + Send + 'static,
 {
-    fn new(ircsky: Ircsky, socket: T) -> Self {
+    fn new(ircsky: Ircsky, socket: T, ip: IpAddr) -> Self {
         let (read, write) = tokio::io::split(socket);
         let read = BufReader::new(read);
+        let sendq = SendQueue::new(write, ircsky.config.irc.sendq, ircsky.metrics.clone());
+        let flood = &ircsky.config.irc.flood;
+        let (commands, publish) = (flood.command_bucket(), flood.publish_bucket());
 
         Self {
             user: UserState::New,
             cap: CapState::New,
             read,
-            write,
+            sendq,
             ircsky,
             line_buffer: Vec::new(),
+            overlong: false,
             empty_lines: 0,
+            next_batch: 0,
+            multiline: None,
+            connected_at: Instant::now(),
+            last_activity: Instant::now(),
+            ping_sent: None,
+            quit_reason: None,
+            commands,
+            publish,
+            lagged_until: Instant::now(),
+            pending_login: None,
+            sasl: None,
+            sasl_login: None,
+            pending_nick: None,
+            ip,
             channels: vec![],
         }
     }
 
     async fn start(mut self) {
-        if let Err(e) = self._start().await {
-            let message = Message::builder("ERROR").trailing(e).build();
-            _ = self.write(message.to_string().as_bytes()).await;
-            _ = self.write.shutdown().await;
+        self.ircsky.metrics.clients.fetch_add(1, Ordering::Relaxed);
+
+        let reason = match self._start().await {
+            Ok(()) => self
+                .quit_reason
+                .take()
+                .unwrap_or_else(|| "Connection closed".to_string()),
+            Err(e) => e.to_string(),
+        };
+
+        let message = Message::builder("ERROR").trailing(&reason).build();
+        _ = self.sendq.force(format!("{}\r\n", message).into_bytes());
+
+        if let Err(e) = self.disconnect(&reason).await {
+            println!("error while disconnecting: {e}");
         }
+        self.sendq.flush().await;
+
+        self.ircsky.metrics.clients.fetch_sub(1, Ordering::Relaxed);
     }
+}
 
+impl<T> IrcClient<T>
+where
+    T: AsyncRead + AsyncWrite,
+{
     async fn _start(&mut self) -> Result<()> {
-        loop {
+        while self.quit_reason.is_none() {
+            let deadline = self.idle_deadline();
+            let max_line_len = self.max_line_len();
+            let limit = (max_line_len - self.line_buffer.len()) as u64;
+            let lagged = self.lagged_until > Instant::now();
+            let authorizing = self.pending_login.is_some();
             let mut map =
                 StreamMap::from_iter(self.channels.iter_mut().map(|(n, c)| (n.as_str(), c)));
 
             tokio::select! {
-                _ = self.read.read_until(b'\n', &mut self.line_buffer) => {
+                // while fakelagged, lines wait in the socket until the lag is over
+                read = read_line(&mut self.read, &mut self.line_buffer, limit), if !lagged => {
                     drop(map);
-                    self.handle_line().await?;
+                    if read? == 0 {
+                        self.quit("Remote host closed the connection");
+                        continue;
+                    }
+                    self.last_activity = Instant::now();
+                    self.ping_sent = None;
+
+                    if !self.line_buffer.ends_with(b"\n") && self.line_buffer.len() >= max_line_len {
+                        // hit the limit mid-line, drop it and skip to the next newline
+                        self.overlong = true;
+                    } else if std::mem::take(&mut self.overlong) {
+                        self.send_input_too_long().await?;
+                    } else {
+                        self.handle_line().await?;
+                    }
                     self.line_buffer.truncate(0);
                 }
 
@@ -132,23 +230,154 @@ where
                     drop(map);
                     self.handle_event(event?).await?;
                 }
+
+                _ = tokio::time::sleep_until(deadline) => {
+                    drop(map);
+                    self.handle_idle().await?;
+                }
+
+                approval = login_approved(&mut self.pending_login), if authorizing => {
+                    drop(map);
+                    self.complete_oauth_login(approval?).await?;
+                }
+
+                _ = tokio::time::sleep_until(self.lagged_until), if lagged => {}
             }
         }
+
+        Ok(())
     }
 
-    pub async fn stop(&mut self) {
-        _ = self.write.shutdown().await;
+    // charges a command to this connection and its DID, delaying the next line
+    // read until both are back in credit
+    fn fakelag(&mut self) -> Result<()> {
+        let flood = &self.ircsky.config.irc.flood;
+
+        let mut lag = self.commands.take();
+        if let Some(did) = self.user.did() {
+            lag = lag.max(self.ircsky.rate_limits.command_lag(did, flood));
+        }
+
+        if lag > flood.max_lag() {
+            anyhow::bail!("Excess Flood");
+        }
+
+        self.lagged_until = Instant::now() + lag;
+        Ok(())
+    }
+
+    pub fn quit(&mut self, reason: impl ToString) {
+        self.quit_reason = Some(reason.to_string());
     }
 
-    async fn write(&mut self, data: &[u8]) -> Result<()> {
-        self.write.write_all(data).await?;
-        self.write.write_all(b"\r\n").await?;
+    // drops the client's subscriptions, takes its DID out of every channel and
+    // tells everyone who shared one of those channels that it quit
+    async fn disconnect(&mut self, reason: &str) -> Result<()> {
+        self.channels.clear();
+        self.ircsky.rate_limits.prune();
+        self.ircsky
+            .dm_queue
+            .prune(self.ircsky.config.irc.dm_queue_expiry());
+
+        let did = match self.user.did() {
+            Some(did) => did.to_owned(),
+            None => return Ok(()),
+        };
+
+        // only forget the DM sender, bot mode, away message and nick, and only
+        // leave their channels, if no other session of theirs is still connected
+        let mut last_session = false;
+        self.ircsky.users.alter(&did, |_, mut user| {
+            if user
+                .sender
+                .as_ref()
+                .is_some_and(|sender| sender.receiver_count() == 0)
+            {
+                last_session = true;
+                user.sender = None;
+                user.bot = false;
+                user.away = None;
+                user.irc_nick = None;
+            }
+            user
+        });
+        if !last_session {
+            return Ok(());
+        }
+        // away for not being connected, while they're still in their channels
+        self.ircsky.broadcast_away(&did);
+
+        let (user_, _) = self.ircsky.get_user(&did).await?;
+        let user = user_.as_ref().clone();
+        drop(user_);
+
+        let mut left = Vec::new();
+        for mut channel in self.ircsky.channels.iter_mut() {
+            if channel.users.remove(&did) {
+                left.push(channel.uri.clone());
+            }
+        }
+
+        let names = left
+            .iter()
+            .filter_map(|uri| Some(self.ircsky.channels.get(uri)?.name.clone()))
+            .collect::<Vec<_>>();
+
+        for uri in left {
+            if let Some(channel) = self.ircsky.channels.get(&uri) {
+                _ = channel.sender.send(PskyEvent::Quit(
+                    user.clone(),
+                    reason.to_string(),
+                    channel.name.clone(),
+                    names.clone(),
+                ));
+            }
+        }
 
         Ok(())
     }
 
+    pub fn registered(&self) -> bool {
+        matches!(self.user, UserState::LoggedIn(..) | UserState::LoggedOut(_))
+    }
+
+    fn idle_deadline(&self) -> Instant {
+        let config = &self.ircsky.config.irc;
+
+        if let Some(ref pending_login) = self.pending_login {
+            pending_login.expires_at
+        } else if !self.registered() {
+            self.connected_at + config.registration_timeout()
+        } else if let Some(ping_sent) = self.ping_sent {
+            ping_sent + config.ping_timeout()
+        } else {
+            self.last_activity + config.ping_interval()
+        }
+    }
+
+    async fn handle_idle(&mut self) -> Result<()> {
+        if self.pending_login.is_some() {
+            anyhow::bail!("Login wasn't approved in time");
+        }
+
+        if !self.registered() {
+            anyhow::bail!("Registration timeout");
+        }
+
+        if self.ping_sent.is_some() {
+            anyhow::bail!(
+                "Ping timeout: {} seconds",
+                self.last_activity.elapsed().as_secs()
+            );
+        }
+
+        self.ping_sent = Some(Instant::now());
+        self.send(Message::builder("PING").trailing("ircsky").build())
+            .await
+    }
+
     pub async fn send(&mut self, message: Message) -> Result<()> {
-        self.write(message.to_string().as_bytes()).await
+        self.sendq.push(format!("{}\r\n", message).into_bytes())
     }
 
     fn received_empty(&mut self) -> Result<()> {
@@ -162,14 +391,31 @@ where
     }
 
     async fn handle_line(&mut self) -> Result<()> {
-        let line = String::from_utf8_lossy(&self.line_buffer);
-        let line = line.trim_end_matches(&['\r', '\n']).trim();
+        let line = match std::str::from_utf8(&self.line_buffer) {
+            Ok(line) => line.trim_end_matches(['\r', '\n']).trim(),
+            Err(_) => {
+                // UTF8ONLY
+                return self
+                    .send(
+                        Message::builder("FAIL")
+                            .param("*")
+                            .param("INVALID_UTF8")
+                            .trailing("Message rejected, it isn't valid UTF-8")
+                            .build(),
+                    )
+                    .await;
+            }
+        };
 
         if line.is_empty() {
             return self.received_empty();
         }
 
-        let message = Message::from(line);
+        if self.line_too_long(line) {
+            return self.send_input_too_long().await;
+        }
+
+        let message = Message::from(normalize_tags(line));
         let message = match message.parse() {
             Ok(message) => message,
             Err(e) => {
@@ -177,12 +423,29 @@ where
             }
         };
 
-        dbg!(&message);
-
         let command = message.command().unwrap_or("NOCOMMAND");
 
+        // lines inside the open multiline batch are paid for when the batch is
+        // published, everything else including BATCH itself, and lines of a
+        // batch that has failed and won't be published, is paid for now
+        let in_multiline = ["PRIVMSG", "NOTICE"]
+            .iter()
+            .any(|c| command.eq_ignore_ascii_case(c))
+            && message.tag("batch").is_some_and(|batch| {
+                self.multiline
+                    .as_ref()
+                    .is_some_and(|multiline| multiline.reference == batch && !multiline.failed)
+            });
+        if !command.eq_ignore_ascii_case("PONG") && !in_multiline {
+            self.fakelag()?;
+        }
+
         match command.to_uppercase().as_str() {
+            "AUTHENTICATE" => self.handle_authenticate(message).await,
+            "AWAY" => self.handle_away(message).await,
+            "BATCH" => self.handle_batch(message).await,
             "CAP" => self.handle_cap(message).await,
+            "CHATHISTORY" => self.handle_chathistory(message).await,
             "JOIN" => self.handle_join(message).await,
             "LIST" => self.handle_list(message).await,
             "MODE" => self.handle_mode(message).await,
@@ -194,68 +457,123 @@ where
             "PONG" => Ok(()),
             "PRIVMSG" => self.handle_privmsg(message).await,
             "QUIT" => self.handle_quit(message).await,
+            "STATS" => self.handle_stats(message).await,
             "TOPIC" => self.handle_topic(message).await,
             "USER" => Ok(()),
             "WHO" => self.handle_who(message).await,
+            "WHOIS" => self.handle_whois(message).await,
             _ => self.handle_other(message).await,
         }
     }
-    async fn handle_event(&mut self, event: PskyEvent) -> Result<()> {
+    pub async fn handle_event(&mut self, event: PskyEvent) -> Result<()> {
         match event {
-            PskyEvent::PrivateMessage(user, message, room) => {
+            PskyEvent::PrivateMessage(user, message, room, meta) => {
                 if let Some(did) = self.user.did() {
-                    if !self.cap.has_capability("echo-message") && user.did == did {
+                    if !self.cap.has_capability("echo-message") && user.did == *did {
                         return Ok(());
                     }
                 }
 
-                for line in message.content.split(&['\r', '\n']) {
-                    self.send(
-                        Message::builder("PRIVMSG")
-                            .prefix(
-                                user.handle.as_ref().ok_or(std::fmt::Error)?,
-                                Some(&user.did),
-                                Some("the.atmosphere"),
-                            )
-                            .param(&room)
-                            .trailing(line)
-                            .build(),
-                    )
+                let privmsg = Message::builder("PRIVMSG")
+                    .prefix(user.nick(), Some(&user.did), Some("the.atmosphere"))
+                    .param(&room);
+                let tags = self.message_tags(&user, &meta);
+                self.send_lines(privmsg, &tags, &room.0, &message.content)
                     .await?;
-                }
             }
             PskyEvent::Join(user, room) => {
                 if let Some(did) = self.user.did() {
-                    if user.did == did {
+                    if user.did == *did {
+                        return Ok(());
+                    }
+                }
+                let join = Message::builder("JOIN")
+                    .tags(&self.user_tags(&user))
+                    .prefix(user.nick(), Some(&user.did), Some("the.atmosphere"))
+                    .param(&room);
+                let join = self.extended_join(join, user.account(), user.realname());
+                self.send(join.build()).await?;
+
+                if let Some(away) = user.away_message() {
+                    if self.cap.has_capability("away-notify") {
+                        self.send_away_notify(&user, Some(away)).await?;
+                    }
+                }
+            }
+            PskyEvent::Part(user, room) => {
+                if let Some(did) = self.user.did() {
+                    if user.did == *did {
                         return Ok(());
                     }
                 }
                 self.send(
-                    Message::builder("JOIN")
-                        .prefix(
-                            user.handle.as_ref().ok_or(std::fmt::Error)?,
-                            Some(&user.did),
-                            Some("the.atmosphere"),
-                        )
+                    Message::builder("PART")
+                        .tags(&self.user_tags(&user))
+                        .prefix(user.nick(), Some(&user.did), Some("the.atmosphere"))
                         .param(&room)
                         .build(),
                 )
                 .await?;
             }
-            PskyEvent::Part(user, room) => {
+            PskyEvent::Quit(user, reason, room, rooms) => {
                 if let Some(did) = self.user.did() {
-                    if user.did == did {
+                    if user.did == *did {
                         return Ok(());
                     }
                 }
+
+                if !self.first_shared(&room, &rooms) {
+                    return Ok(());
+                }
+
                 self.send(
-                    Message::builder("PART")
-                        .prefix(
-                            user.handle.as_ref().ok_or(std::fmt::Error)?,
-                            Some(&user.did),
-                            Some("the.atmosphere"),
-                        )
-                        .param(&room)
+                    Message::builder("QUIT")
+                        .tags(&self.user_tags(&user))
+                        .prefix(user.nick(), Some(&user.did), Some("the.atmosphere"))
+                        .trailing(reason)
+                        .build(),
+                )
+                .await?;
+            }
+            PskyEvent::Away(user, room, rooms) => {
+                if let Some(did) = self.user.did() {
+                    if user.did == *did {
+                        return Ok(());
+                    }
+                }
+
+                if !self.cap.has_capability("away-notify") || !self.first_shared(&room, &rooms) {
+                    return Ok(());
+                }
+
+                self.send_away_notify(&user, user.away_message()).await?;
+            }
+            PskyEvent::Nick(user, old, room, rooms) => {
+                let new = user.nick();
+
+                // our own nick changes when our handle does
+                if self.user.did() == Some(&user.did) {
+                    if self.user.nick() != Some(new.as_str()) {
+                        self.user.set_nick(new.clone());
+                        self.send_nick_change(&user, &old, &new).await?;
+                    }
+                    return Ok(());
+                }
+
+                if self.first_shared(&room, &rooms) {
+                    self.send_nick_change(&user, &old, &new).await?;
+                }
+            }
+            PskyEvent::Account(user, room, rooms) => {
+                if !self.cap.has_capability("account-notify") || !self.first_shared(&room, &rooms) {
+                    return Ok(());
+                }
+
+                self.send(
+                    Message::builder("ACCOUNT")
+                        .tags(&self.user_tags(&user))
+                        .prefix(user.nick(), Some(&user.did), Some("the.atmosphere"))
+                        .param(user.account().unwrap_or("*"))
                         .build(),
                 )
                 .await?;
@@ -263,6 +581,31 @@ where
         }
         Ok(())
     }
+
+    // events about a user are broadcast to every channel they're in, only
+    // relay them once, from the first of those channels we're in
+    fn first_shared(&self, room: &ChannelName, rooms: &[ChannelName]) -> bool {
+        self.channels
+            .iter()
+            .find(|(name, _)| rooms.iter().any(|r| &r.0 == name))
+            .is_some_and(|(name, _)| *name == room.0)
+    }
+}
+
+// the code and issuer of an approved OAuth login, never resolves without one
+async fn login_approved(pending_login: &mut Option<PendingLogin>) -> Result<(String, String)> {
+    match pending_login {
+        Some(pending_login) => pending_login.approved().await,
+        None => std::future::pending().await,
+    }
+}
+
+// reads up to and including the next newline, but no more than `limit` bytes
+async fn read_line<R>(read: &mut R, buf: &mut Vec<u8>, limit: u64) -> std::io::Result<usize>
+where
+    R: AsyncBufRead + Unpin,
+{
+    read.take(limit).read_until(b'\n', buf).await
 }
 
 impl Ircsky {
@@ -280,16 +623,16 @@ impl Ircsky {
         // TODO: facets
 
         loop {
-            let (socket, _) = listener.accept().await.unwrap();
+            let (socket, addr) = listener.accept().await.unwrap();
             let tls_acceptor = tls_acceptor.clone();
             let ircsky = self.clone();
 
             tokio::spawn(async move {
                 if let Some(tls_acceptor) = tls_acceptor {
                     let tls_socket = tls_acceptor.accept(socket).await.unwrap();
-                    IrcClient::new(ircsky, tls_socket).start().await;
+                    IrcClient::new(ircsky, tls_socket, addr.ip()).start().await;
                 } else {
-                    IrcClient::new(ircsky, socket).start().await;
+                    IrcClient::new(ircsky, socket, addr.ip()).start().await;
                 }
             });
         }
@@ -0,0 +1,56 @@
+use anyhow::Result;
+use irc_rust::Message;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::irc::IrcClient;
+use crate::ircsky::CHANNELLEN;
+use crate::nick::NICKLEN;
+
+// clients may not handle more than 13 tokens in a single 005
+const TOKENS_PER_LINE: usize = 13;
+
+pub const AWAYLEN: usize = 200;
+
+impl<T> IrcClient<T>
+where
+    T: AsyncRead + AsyncWrite,
+{
+    // everything here has to match what the command handlers actually do
+    fn isupport(&self) -> Vec<String> {
+        let config = &self.ircsky.config.irc;
+
+        vec![
+            format!("AWAYLEN={AWAYLEN}"),
+            "BOT=B".to_string(),
+            "CASEMAPPING=ascii".to_string(),
+            "CHANMODES=,,,nrt".to_string(),
+            format!("CHANNELLEN={CHANNELLEN}"),
+            "CHANTYPES=#".to_string(),
+            format!("CHATHISTORY={}", config.chathistory),
+            format!("LINELEN={}", config.linelen),
+            "MSGREFTYPES=msgid,timestamp".to_string(),
+            format!("NETWORK={}", config.network),
+            format!("NICKLEN={NICKLEN}"),
+            "PREFIX=".to_string(),
+            "TARGMAX=JOIN:,NAMES:,PART:1,PRIVMSG:1,WHO:1,WHOIS:1".to_string(),
+            "UTF8ONLY".to_string(),
+            "WHOX".to_string(),
+        ]
+    }
+
+    pub async fn send_isupport(&mut self) -> Result<()> {
+        let nick = self.user.get_nick()?.to_owned();
+
+        for tokens in self.isupport().chunks(TOKENS_PER_LINE) {
+            let message = tokens
+                .iter()
+                .fold(Message::builder("005").param(&nick), |message, token| {
+                    message.param(token)
+                });
+            self.send(message.trailing("are supported by this server").build())
+                .await?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,181 @@
+use anyhow::Result;
+use irc_rust::{builder::Builder, Message};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::irc::{IrcClient, WithTags};
+
+// message tags have their own budget and don't count towards LINELEN
+pub const MAX_CLIENT_TAGS_LEN: usize = 4096;
+
+// chunks of at most max bytes, broken after a space where possible and never
+// inside a character
+pub fn split_at_words(text: &str, max: usize) -> Vec<&str> {
+    let mut chunks = Vec::new();
+    let mut rest = text;
+
+    while rest.len() > max {
+        let mut end = max;
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+
+        if let Some(space) = rest[..end].rfind(' ').filter(|&space| space > 0) {
+            end = space + 1;
+        }
+
+        if end == 0 {
+            // budget is smaller than a single character, send it anyway
+            end = rest.chars().next().map_or(rest.len(), char::len_utf8);
+        }
+
+        chunks.push(&rest[..end]);
+        rest = &rest[end..];
+    }
+
+    chunks.push(rest);
+    chunks
+}
+
+// irc-rust can't parse a tag without = when another tag follows it
+pub fn normalize_tags(line: &str) -> String {
+    let (tags, rest) = match line
+        .strip_prefix('@')
+        .and_then(|tagged| tagged.split_once(' '))
+    {
+        Some(split) => split,
+        None => return line.to_string(),
+    };
+
+    let tags = tags
+        .split(';')
+        .filter(|tag| !tag.is_empty())
+        .map(|tag| {
+            if tag.contains('=') {
+                tag.to_string()
+            } else {
+                format!("{tag}=")
+            }
+        })
+        .collect::<Vec<_>>();
+
+    format!("@{} {}", tags.join(";"), rest)
+}
+
+fn untagged(line: &str) -> &str {
+    match line.strip_prefix('@') {
+        Some(tagged) => tagged.split_once(' ').map_or("", |(_, rest)| rest),
+        None => line,
+    }
+}
+
+impl<T> IrcClient<T>
+where
+    T: AsyncRead + AsyncWrite,
+{
+    pub fn max_line_len(&self) -> usize {
+        MAX_CLIENT_TAGS_LEN + self.ircsky.config.irc.linelen
+    }
+
+    // checks a complete line against LINELEN, not counting its tags
+    pub fn line_too_long(&self, line: &str) -> bool {
+        let body = untagged(line);
+        line.len() - body.len() > MAX_CLIENT_TAGS_LEN
+            || body.len() + 2 > self.ircsky.config.irc.linelen
+    }
+
+    pub async fn send_input_too_long(&mut self) -> Result<()> {
+        self.send(
+            Message::builder("417")
+                .param(self.user.nick().unwrap_or("*"))
+                .trailing("Input line was too long")
+                .build(),
+        )
+        .await
+    }
+
+    // how many bytes of trailing parameter fit in `message` without going over LINELEN
+    fn trailing_budget(&self, message: &Builder) -> usize {
+        let line = message.clone().trailing("").build().to_string();
+        self.ircsky
+            .config
+            .irc
+            .linelen
+            .saturating_sub(untagged(&line).len() + 2)
+    }
+
+    // as many messages as it takes to stay within LINELEN
+    pub async fn send_split(&mut self, message: Builder, text: &str) -> Result<()> {
+        let max = self.trailing_budget(&message);
+        for chunk in split_at_words(text, max) {
+            self.send(message.clone().trailing(chunk).build()).await?;
+        }
+        Ok(())
+    }
+
+    // a draft/multiline batch if negotiated, one message per non-empty line
+    // otherwise, tags go on the batch when there is one
+    pub async fn send_lines(
+        &mut self,
+        message: Builder,
+        tags: &[(&str, String)],
+        target: &str,
+        text: &str,
+    ) -> Result<()> {
+        let lines = text.split('\n').map(|line| line.trim_end_matches('\r'));
+
+        if !self.cap.has_capability("draft/multiline") {
+            let message = message.tags(tags);
+            for line in lines.filter(|line| !line.is_empty()) {
+                self.send_split(message.clone(), line).await?;
+            }
+            return Ok(());
+        }
+
+        let max = self.trailing_budget(&message);
+        let lines = lines
+            .map(|line| split_at_words(line, max))
+            .collect::<Vec<_>>();
+
+        if let [line] = lines.as_slice() {
+            if let [chunk] = line.as_slice() {
+                if !chunk.is_empty() {
+                    self.send(message.tags(tags).trailing(chunk).build())
+                        .await?;
+                }
+                return Ok(());
+            }
+        }
+
+        self.next_batch += 1;
+        let batch = format!("ml{}", self.next_batch);
+
+        self.send(
+            Message::builder("BATCH")
+                .tags(tags)
+                .prefix("ircsky", None::<String>, None::<String>)
+                .param(format!("+{batch}"))
+                .param("draft/multiline")
+                .param(target)
+                .build(),
+        )
+        .await?;
+
+        for line in lines {
+            for (i, chunk) in line.into_iter().enumerate() {
+                let mut part = message.clone().tag("batch", &batch);
+                if i > 0 {
+                    part = part.tag("draft/multiline-concat", "");
+                }
+                self.send(part.trailing(chunk).build()).await?;
+            }
+        }
+
+        self.send(
+            Message::builder("BATCH")
+                .prefix("ircsky", None::<String>, None::<String>)
+                .param(format!("-{batch}"))
+                .build(),
+        )
+        .await
+    }
+}
@@ -1,7 +1,15 @@
 mod command;
 mod irc_client;
+mod isupport;
+mod line;
 mod param_maybe;
 mod registration;
+mod send_queue;
+mod tags;
 
 pub use irc_client::*;
+pub use isupport::*;
+pub use line::*;
 pub use param_maybe::*;
+pub use send_queue::*;
+pub use tags::*;
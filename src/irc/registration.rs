@@ -12,7 +12,7 @@ where
 {
     pub async fn register_user(&mut self) -> Result<()> {
         let nick = self.user.get_nick()?.to_owned();
-        let did = self.user.did().to_owned().unwrap_or("logged-out");
+        let did = self.user.did().map_or("logged-out", |did| did.as_str());
 
         self.send(
             Message::builder("001")
@@ -43,20 +43,13 @@ where
                 .param(&nick)
                 .param("ircsky")
                 .param("1")
-                .param("+")
-                .param("+t")
+                .param("B")
+                .param("nrt")
                 .build(),
         )
         .await?;
 
-        self.send(
-            Message::builder("005")
-                .param(&nick)
-                .param("IRCSKY")
-                .trailing("are supported by this server")
-                .build(),
-        )
-        .await?;
+        self.send_isupport().await?;
 
         // MAY then send other numerics and messages
         // TODO: SHOULD then respond as though the client sent the LUSERS command
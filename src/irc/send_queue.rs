@@ -0,0 +1,155 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use crate::metrics::Metrics;
+
+// how long a closing connection gets to flush what's left in its queue
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(10);
+
+enum Outbound {
+    Line(Vec<u8>),
+    Close,
+}
+
+#[derive(Default)]
+struct Depth {
+    lines: AtomicUsize,
+    bytes: AtomicUsize,
+}
+
+impl Depth {
+    fn add(&self, bytes: usize) {
+        self.lines.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn sub(&self, lines: usize, bytes: usize) {
+        self.lines.fetch_sub(lines, Ordering::Relaxed);
+        self.bytes.fetch_sub(bytes, Ordering::Relaxed);
+    }
+}
+
+// outbound lines for one connection, written by their own task so a slow
+// reader never blocks the client's select loop
+pub struct SendQueue {
+    tx: mpsc::UnboundedSender<Outbound>,
+    depth: Arc<Depth>,
+    limit: usize,
+    metrics: Arc<Metrics>,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl SendQueue {
+    pub fn new<W>(write: W, limit: usize, metrics: Arc<Metrics>) -> Self
+    where
+        W: AsyncWrite + Send + Unpin + 'static,
+    {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let depth = Arc::new(Depth::default());
+        let writer = tokio::spawn(drain(write, rx, depth.clone(), metrics.clone()));
+
+        Self {
+            tx,
+            depth,
+            limit,
+            metrics,
+            writer: Some(writer),
+        }
+    }
+
+    // lines and bytes waiting to be written
+    pub fn depth(&self) -> (usize, usize) {
+        (
+            self.depth.lines.load(Ordering::Relaxed),
+            self.depth.bytes.load(Ordering::Relaxed),
+        )
+    }
+
+    pub fn push(&self, line: Vec<u8>) -> Result<()> {
+        if self.depth.bytes.load(Ordering::Relaxed) + line.len() > self.limit {
+            self.metrics.sendq_exceeded.fetch_add(1, Ordering::Relaxed);
+            anyhow::bail!("Max SendQ exceeded");
+        }
+        self.force(line)
+    }
+
+    // over the SendQ limit or not, for the final ERROR
+    pub fn force(&self, line: Vec<u8>) -> Result<()> {
+        let len = line.len();
+        self.depth.add(len);
+        self.metrics.queued(len);
+
+        if self.tx.send(Outbound::Line(line)).is_err() {
+            self.depth.sub(1, len);
+            self.metrics.dequeued(1, len);
+            anyhow::bail!("Connection closed");
+        }
+        Ok(())
+    }
+
+    // shuts the socket down once everything queued before this is written
+    pub fn close(&self) {
+        _ = self.tx.send(Outbound::Close);
+    }
+
+    // gives up after FLUSH_TIMEOUT if the peer isn't reading
+    pub async fn flush(&mut self) {
+        self.close();
+        if let Some(mut writer) = self.writer.take() {
+            if tokio::time::timeout(FLUSH_TIMEOUT, &mut writer)
+                .await
+                .is_err()
+            {
+                writer.abort();
+            }
+        }
+    }
+}
+
+// whatever is still queued when the writer stops (or is aborted) is dropped,
+// so take it off the depth counters and the server-wide metrics
+struct DepthGuard {
+    depth: Arc<Depth>,
+    metrics: Arc<Metrics>,
+}
+
+impl Drop for DepthGuard {
+    fn drop(&mut self) {
+        let lines = self.depth.lines.swap(0, Ordering::Relaxed);
+        let bytes = self.depth.bytes.swap(0, Ordering::Relaxed);
+        self.metrics.dequeued(lines, bytes);
+    }
+}
+
+async fn drain<W>(
+    mut write: W,
+    mut rx: mpsc::UnboundedReceiver<Outbound>,
+    depth: Arc<Depth>,
+    metrics: Arc<Metrics>,
+) where
+    W: AsyncWrite + Unpin,
+{
+    let _guard = DepthGuard {
+        depth: depth.clone(),
+        metrics: metrics.clone(),
+    };
+
+    while let Some(Outbound::Line(line)) = rx.recv().await {
+        let result = write.write_all(&line).await;
+        depth.sub(1, line.len());
+        metrics.dequeued(1, line.len());
+
+        if result.is_err() {
+            break;
+        }
+    }
+
+    rx.close();
+    _ = write.shutdown().await;
+}
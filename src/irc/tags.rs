@@ -0,0 +1,72 @@
+use irc_rust::builder::Builder;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::irc::IrcClient;
+use crate::ircsky::User;
+use crate::psky::MessageMeta;
+
+pub fn escape_tag_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            ';' => escaped.push_str("\\:"),
+            ' ' => escaped.push_str("\\s"),
+            '\\' => escaped.push_str("\\\\"),
+            '\r' => escaped.push_str("\\r"),
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+pub trait WithTags {
+    fn tags(self, tags: &[(&str, String)]) -> Builder;
+}
+
+impl WithTags for Builder {
+    fn tags(self, tags: &[(&str, String)]) -> Builder {
+        tags.iter()
+            .fold(self, |builder, (key, value)| builder.tag(key, value))
+    }
+}
+
+impl<T> IrcClient<T>
+where
+    T: AsyncRead + AsyncWrite,
+{
+    // tags for a relayed message, limited to what the client negotiated
+    pub fn message_tags(&self, user: &User, meta: &MessageMeta) -> Vec<(&'static str, String)> {
+        let mut tags = Vec::new();
+
+        if self.cap.has_capability("server-time") {
+            tags.push(("time", meta.time()));
+        }
+
+        if self.cap.has_capability("message-tags") {
+            if let Some(msgid) = &meta.msgid {
+                tags.push(("msgid", escape_tag_value(msgid)));
+            }
+        }
+
+        tags.extend(self.user_tags(user));
+        tags
+    }
+
+    // tags for anything relayed from a user
+    pub fn user_tags(&self, user: &User) -> Vec<(&'static str, String)> {
+        let mut tags = Vec::new();
+
+        if self.cap.has_capability("account-tag") {
+            if let Some(account) = user.account() {
+                tags.push(("account", escape_tag_value(account)));
+            }
+        }
+
+        if self.cap.has_capability("message-tags") && user.bot {
+            tags.push(("bot", String::new()));
+        }
+
+        tags
+    }
+}
@@ -1,75 +1,213 @@
 use anyhow::Result;
-use dashmap::DashMap;
-use std::collections::HashSet;
+use dashmap::{mapref::entry::Entry, DashMap};
+use std::collections::{HashSet, VecDeque};
+use std::fmt::Write;
 use std::ops::Deref;
 use std::sync::Arc;
 
 use crate::atproto;
+use crate::aturi::{AtUri, Nsid, RecordKey};
 use crate::config::Settings;
+use crate::did::Did;
+use crate::dm::DmQueue;
+use crate::http::Http;
+use crate::identity::IdentityCache;
+use crate::metrics::Metrics;
+use crate::nick;
+use crate::oauth::OAuth;
 use crate::psky;
+use crate::ratelimit::RateLimits;
+use crate::resolver::{HandleResolver, NativeResolver};
 
 #[derive(Clone)]
 pub struct Ircsky {
-    pub users: Arc<DashMap<String, User>>,
+    pub users: Arc<DashMap<Did, User>>,
     pub channels: Arc<DashMap<ChannelUri, Channel>>,
     channel_name_map: Arc<DashMap<ChannelName, ChannelUri>>,
     pub config: Arc<Settings>,
+    pub metrics: Arc<Metrics>,
+    pub rate_limits: Arc<RateLimits>,
+    pub dm_queue: Arc<DmQueue>,
+    pub oauth: Option<Arc<OAuth>>,
+    pub resolver: Arc<dyn HandleResolver>,
+    pub identity: Arc<IdentityCache>,
+    pub http: Http,
 }
 
+// #, a room name of up to 64 bytes that may all be escaped, @ and a handle
+pub const CHANNELLEN: usize = 1 + 3 * 64 + 1 + atproto::MAX_HANDLE_LEN;
+
+// a room name can't have these or control characters in a channel name, and
+// an @ would split it at the wrong place, they become %hh like the % itself
+const ROOM_ESCAPED: &str = " ,@%";
+
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct ChannelName(pub String);
+
+impl ChannelName {
+    // #<room>@<handle>, the one name a room goes by
+    pub fn new(room: &str, handle: &str) -> ChannelName {
+        let mut name = String::with_capacity(1 + room.len() + 1 + handle.len());
+        name.push('#');
+        for c in room.chars() {
+            if c.is_control() || ROOM_ESCAPED.contains(c) {
+                for b in c.to_string().bytes() {
+                    _ = write!(name, "%{b:02X}");
+                }
+            } else {
+                name.push(c);
+            }
+        }
+        name.push('@');
+        name.push_str(&handle.to_ascii_lowercase());
+        ChannelName(name)
+    }
+
+    // the room name and handle a channel name was made from
+    pub fn parse(&self) -> Option<(String, String)> {
+        let (room, handle) = self.0.strip_prefix('#')?.rsplit_once('@')?;
+        if room.is_empty() || handle.is_empty() {
+            return None;
+        }
+
+        let mut bytes = Vec::with_capacity(room.len());
+        let mut rest = room.as_bytes();
+        while let Some((&b, tail)) = rest.split_first() {
+            rest = tail;
+            if b == b'%' {
+                let hex = std::str::from_utf8(rest.get(..2)?).ok()?;
+                bytes.push(u8::from_str_radix(hex, 16).ok()?);
+                rest = &rest[2..];
+            } else {
+                bytes.push(b);
+            }
+        }
+        Some((String::from_utf8(bytes).ok()?, handle.to_ascii_lowercase()))
+    }
+
+    // CASEMAPPING=ascii
+    pub fn folded(&self) -> ChannelName {
+        ChannelName(self.0.to_ascii_lowercase())
+    }
+}
 impl std::fmt::Display for ChannelName {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}", self.0)
     }
 }
-#[derive(Clone, PartialEq, Eq, Hash, Debug, serde::Deserialize, serde::Serialize)]
-pub struct ChannelUri(pub String);
+
+pub const ROOM_COLLECTION: &str = "social.psky.chat.room";
+
+// a room record, at://<did>/social.psky.chat.room/<rkey>
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct ChannelUri {
+    pub did: Did,
+    pub rkey: RecordKey,
+}
+
+impl ChannelUri {
+    pub fn at_uri(&self) -> AtUri {
+        AtUri::record(
+            self.did.clone(),
+            Nsid::parse(ROOM_COLLECTION).expect("Room collection is a valid NSID"),
+            self.rkey.clone(),
+        )
+    }
+}
+
+// only room records are channels
+impl TryFrom<&AtUri> for ChannelUri {
+    type Error = anyhow::Error;
+
+    fn try_from(uri: &AtUri) -> Result<ChannelUri> {
+        match (&uri.collection, &uri.rkey) {
+            (Some(collection), Some(rkey)) if collection.as_str() == ROOM_COLLECTION => {
+                Ok(ChannelUri {
+                    did: uri.authority.clone(),
+                    rkey: rkey.clone(),
+                })
+            }
+            _ => anyhow::bail!("{uri} isn't a room"),
+        }
+    }
+}
+
+impl std::fmt::Display for ChannelUri {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.at_uri())
+    }
+}
 
 pub struct Channel {
     pub uri: ChannelUri,
     pub name: ChannelName,
     pub sender: tokio::sync::broadcast::Sender<psky::PskyEvent>,
-    pub users: HashSet<String>,
+    pub users: HashSet<Did>,
     pub room: psky::Room,
+    pub history: VecDeque<HistoryEntry>,
+}
+
+#[derive(Clone, Debug)]
+pub struct HistoryEntry {
+    pub user: User,
+    pub content: String,
+    pub meta: psky::MessageMeta,
 }
 
 impl Ircsky {
     pub fn new(config: Settings) -> Self {
+        let http = Http::new(&config.atproto).expect("Failed to build the HTTP client");
+        let resolver = Arc::new(NativeResolver::new(&config.atproto, http.clone()));
+        Self::with_resolver(config, http, resolver)
+    }
+
+    // handles resolved by something other than DNS and HTTPS
+    pub fn with_resolver(config: Settings, http: Http, resolver: Arc<dyn HandleResolver>) -> Self {
+        let oauth = config
+            .oauth
+            .clone()
+            .map(|oauth| Arc::new(OAuth::new(oauth, http.clone())));
+        let identity = Arc::new(IdentityCache::new(&config.atproto));
+
         Self {
             users: Arc::new(DashMap::new()),
             channels: Arc::new(DashMap::new()),
             channel_name_map: Arc::new(DashMap::new()),
             config: Arc::new(config),
+            metrics: Arc::new(Metrics::default()),
+            rate_limits: Arc::new(RateLimits::default()),
+            dm_queue: Arc::new(DmQueue::default()),
+            oauth,
+            resolver,
+            identity,
+            http,
         }
     }
 
     pub async fn run(&mut self) -> Result<()> {
         let _ = tokio::join!(
             self.clone().start_jetstream(),
-            self.clone().start_irc_server()
+            self.clone().start_irc_server(),
+            self.clone().start_oauth_server()
         );
 
         Ok(())
     }
 
     pub async fn resolve_channel(&self, channel: &ChannelName) -> Option<ChannelUri> {
-        if let Some(channel_uri) = self.channel_name_map.get(channel) {
+        let folded = channel.folded();
+        if let Some(channel_uri) = self.channel_name_map.get(&folded) {
             return Some(channel_uri.value().clone());
         }
 
-        // parse channel name:
-        // starts with #, then utf-8 chars, then @, then a handle
-        let (head, tail) = channel.0.split_at(1);
-        if head != "#" {
+        if channel.0.len() > CHANNELLEN {
             return None;
         }
 
-        let (_, handle) = tail.split_at(tail.find('@')?);
-        let handle = &handle[1..]; // skip the @
+        let (_, handle) = channel.parse()?;
 
-        let did = atproto::resolve_handle(handle).await.ok()?;
-        let pds = atproto::get_pds(&did).await.ok()?;
+        let did = self.resolve_handle(&handle).await.ok()?;
+        let pds = self.get_pds(&did).await.ok()?;
         // we get the handle's pds, call listRecords, insert every room they have
 
         #[derive(serde::Deserialize, Debug)]
@@ -84,99 +222,197 @@ impl Ircsky {
         }
 
         // 3. from pds, get profile, cache
-        let rooms = reqwest::get(&format!(
-            "{}/xrpc/com.atproto.repo.listRecords?repo={}&collection=social.psky.chat.room",
-            pds, did
-        ))
-        .await
-        .ok()?
-        .json::<ListRooms>()
-        .await
-        .map(|gp| gp.records)
-        .ok()?;
-
-        dbg!(&rooms);
+        let rooms = self
+            .http
+            .get(&format!(
+                "{}/xrpc/com.atproto.repo.listRecords?repo={}&collection={}",
+                pds, did, ROOM_COLLECTION
+            ))
+            .await
+            .ok()?
+            .json::<ListRooms>()
+            .await
+            .map(|gp| gp.records)
+            .ok()?;
 
         for room in rooms {
-            self.channel_name_map.insert(
-                ChannelName(format!("#{}@{}", room.value.name, handle)),
-                ChannelUri(room.uri.clone()),
-            );
-            self.channels.insert(
-                ChannelUri(room.uri.clone()),
-                Channel {
-                    uri: ChannelUri(room.uri),
-                    name: ChannelName(format!("#{}@{}", room.value.name, handle)),
-                    sender: tokio::sync::broadcast::channel(16).0,
-                    users: HashSet::new(),
-                    room: room.value,
-                },
-            );
+            // a record listed from someone else's repo or collection isn't
+            // one of their rooms
+            let uri = match AtUri::parse(&room.uri).and_then(|uri| ChannelUri::try_from(&uri)) {
+                Ok(uri) if uri.did == did => uri,
+                _ => continue,
+            };
+
+            self.add_channel(uri, &handle, room.value);
         }
 
         self.channel_name_map
-            .get(channel)
+            .get(&folded)
             .map(|uri| uri.value().clone())
     }
 
+    // a room under its name, or an update to one we already have, which keeps
+    // its users and history but goes by the new name if it was renamed
+    pub fn add_channel(&self, uri: ChannelUri, handle: &str, room: psky::Room) {
+        let name = ChannelName::new(&room.name, handle);
+        self.channel_name_map.insert(name.folded(), uri.clone());
+
+        match self.channels.entry(uri) {
+            Entry::Occupied(mut channel) => {
+                let channel = channel.get_mut();
+                let old = std::mem::replace(&mut channel.name, name).folded();
+                // unless the old name has been taken by another room since
+                if old != channel.name.folded() {
+                    self.channel_name_map
+                        .remove_if(&old, |_, uri| *uri == channel.uri);
+                }
+                channel.room = room;
+            }
+            Entry::Vacant(channel) => {
+                let uri = channel.key().clone();
+                channel.insert(Channel {
+                    uri,
+                    name,
+                    sender: tokio::sync::broadcast::channel(16).0,
+                    users: HashSet::new(),
+                    room,
+                    history: VecDeque::new(),
+                });
+            }
+        }
+    }
+
     pub async fn channel_name(&self, channel: &ChannelUri) -> Option<ChannelName> {
         Some(self.channels.get(channel)?.name.clone())
     }
 
-    pub async fn get_user<'a>(&'a self, did: &str) -> Result<(impl AsRef<User> + 'a, bool)> {
-        // 0. check cache
+    // sends an event to every channel the user is in, built from the channel
+    // it's sent to and the list of all of them
+    pub fn broadcast_to_user_channels(
+        &self,
+        did: &str,
+        event: impl Fn(ChannelName, Vec<ChannelName>) -> psky::PskyEvent,
+    ) {
+        let channels = self
+            .channels
+            .iter()
+            .filter(|channel| channel.users.contains(did))
+            .map(|channel| (channel.sender.clone(), channel.name.clone()))
+            .collect::<Vec<_>>();
+        let names = channels
+            .iter()
+            .map(|(_, name)| name.clone())
+            .collect::<Vec<_>>();
+
+        for (sender, name) in channels {
+            _ = sender.send(event(name, names.clone()));
+        }
+    }
+
+    // tells everyone sharing a channel with the user what they're away with now
+    pub fn broadcast_away(&self, did: &str) {
+        if let Some(user) = self.users.get(did).map(|user| user.clone()) {
+            self.broadcast_to_user_channels(did, |name, names| {
+                psky::PskyEvent::Away(user.clone(), name, names)
+            });
+        }
+    }
+
+    // the DID behind a nick, or behind a DID or handle used as one
+    pub async fn resolve_nick(&self, nick: &str) -> Result<Did> {
+        if nick.starts_with("did:") {
+            return Did::parse(nick);
+        }
+
+        // known users first, shortened nicks can only be found this way
+        if let Some(user) = self.users.iter().find(|user| nick::eq(&user.nick(), nick)) {
+            return Ok(user.did.clone());
+        }
+
+        match nick::unescape(nick) {
+            Some(did) if did.starts_with("did:") => Did::parse(&did),
+            Some(handle) => self.resolve_handle(&handle).await,
+            None => anyhow::bail!("Unknown nick {nick}"),
+        }
+    }
+
+    // a handle belongs to one DID at a time, any other user still holding it
+    // is stale and goes back to a nick from their DID
+    pub fn claim_handle(&self, did: &str, handle: &str) {
+        for mut user in self.users.iter_mut() {
+            if user.did != *did
+                && user
+                    .handle
+                    .as_ref()
+                    .is_some_and(|held| held.eq_ignore_ascii_case(handle))
+            {
+                user.handle = None;
+            }
+        }
+    }
+
+    pub async fn get_user<'a>(&'a self, did: &Did) -> Result<(impl AsRef<User> + 'a, bool)> {
+        // 0. check cache, known users are looked up again once their DID
+        // document expires
         if let Some(user) = self.users.get(did) {
-            return Ok((user, true));
+            if self.identity.docs.is_fresh(did) {
+                return Ok((user, true));
+            }
         }
 
-        // 1. resolve did (get did doc)
-        let did_doc = atproto::get_did_doc(did).await?;
+        // 1. resolve did (get did doc), a known user stays as they were if
+        // that fails
+        let did_doc = match self.get_did_doc(did).await {
+            Ok(did_doc) => did_doc,
+            Err(e) => match self.users.get(did) {
+                Some(user) => return Ok((user, true)),
+                None => return Err(e),
+            },
+        };
 
         // 2. from did doc, get pds
-        let pds = &did_doc
-            .service
-            .iter()
-            .find(|s| s.id == "#atproto_pds" && s.r#type == "AtprotoPersonalDataServer")
-            .ok_or(anyhow::anyhow!("pds not found"))?
-            .service_endpoint;
-
-        let mut claimed_handle = did_doc
-            .also_known_as
-            .iter()
-            .find(|aka| aka.starts_with("at://"))
-            .map(|aka| aka[5..].to_string());
+        let pds = did_doc.pds().ok_or(anyhow::anyhow!("pds not found"))?;
 
         // verify
+        let mut claimed_handle = did_doc.handle().map(str::to_string);
         if let Some(claimed) = &claimed_handle {
-            let resolved_did = atproto::resolve_handle(claimed).await?;
-            if resolved_did != did {
+            if self.resolve_handle(claimed).await.ok().as_ref() != Some(did) {
                 claimed_handle = None;
             }
         }
 
-        #[derive(serde::Deserialize)]
-        struct GetProfile {
-            value: psky::Profile,
+        // 3. from pds, get profile
+        let profile = self.get_profile(did, pds).await;
+
+        if self.users.contains_key(did) {
+            self.users.alter(did, |_, mut user| {
+                user.pds = pds.to_string();
+                if let Ok(profile) = profile {
+                    user.profile = profile;
+                }
+                user
+            });
+            self.update_handle(did, claimed_handle);
+        } else {
+            if let Some(handle) = &claimed_handle {
+                self.claim_handle(did, handle);
+            }
+            self.users.insert(
+                did.clone(),
+                User {
+                    did: did.clone(),
+                    pds: pds.to_string(),
+                    profile: profile.ok().flatten(),
+                    handle: claimed_handle,
+                    sender: None,
+                    bot: false,
+                    away: None,
+                    active: true,
+                    irc_nick: None,
+                },
+            );
         }
 
-        // 3. from pds, get profile, cache
-        let profile = reqwest::get(&format!(
-            "{}/xrpc/com.atproto.repo.getRecord?repo={}&collection=social.psky.actor.profile&rkey=self",
-            pds, did
-        ))
-        .await?
-        .json::<GetProfile>()
-        .await
-        .map(|gp| gp.value)
-        .ok();
-
-        let ret = User {
-            did: did.to_string(),
-            profile,
-            handle: claimed_handle,
-            sender: None,
-        };
-        self.users.insert(did.to_string(), ret);
         Ok((
             self.users
                 .get(did)
@@ -186,7 +422,7 @@ impl Ircsky {
     }
 }
 
-impl AsRef<User> for dashmap::mapref::one::Ref<'_, String, User> {
+impl AsRef<User> for dashmap::mapref::one::Ref<'_, Did, User> {
     fn as_ref(&self) -> &User {
         self.deref()
     }
@@ -194,8 +430,130 @@ impl AsRef<User> for dashmap::mapref::one::Ref<'_, String, User> {
 
 #[derive(Debug, Clone)]
 pub struct User {
-    pub did: String,
+    pub did: Did,
+    pub pds: String,
     pub profile: Option<psky::Profile>,
     pub handle: Option<String>,
     pub sender: Option<tokio::sync::broadcast::Sender<psky::PskyEvent>>,
+    // set with MODE +B by the user's IRC session
+    pub bot: bool,
+    // set with AWAY by the user's IRC session
+    pub away: Option<String>,
+    // false while the account is deactivated, suspended or taken down
+    pub active: bool,
+    // set with NICK by the user's IRC session
+    pub irc_nick: Option<String>,
+}
+
+impl User {
+    // the DID is the account, for account-tag, account-notify and extended-join
+    pub fn account(&self) -> Option<&str> {
+        self.active.then_some(self.did.as_str())
+    }
+
+    // the one picked with NICK, otherwise from the verified handle, or the DID
+    // without one
+    pub fn nick(&self) -> String {
+        match self.irc_nick {
+            Some(ref irc_nick) => irc_nick.clone(),
+            None => nick::from_identity(self.handle.as_deref(), &self.did),
+        }
+    }
+
+    pub fn realname(&self) -> &str {
+        self.profile
+            .as_ref()
+            .and_then(|profile| profile.nickname.as_deref())
+            .or(self.handle.as_deref())
+            .unwrap_or(&self.did)
+    }
+
+    // users without a connected IRC session are only on psky, and count as away
+    pub fn away_message(&self) -> Option<&str> {
+        match self.away {
+            Some(ref away) => Some(away),
+            None if self.sender.is_none() => Some("Not connected over IRC"),
+            None => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::tests::settings;
+    use crate::resolver::tests::Fake;
+
+    #[test]
+    fn channel_names() {
+        for (room, handle, name) in [
+            ("general", "Psky.Social", "#general@psky.social"),
+            ("a b,c@d%e", "alice.test", "#a%20b%2Cc%40d%25e@alice.test"),
+            ("bell\x07", "alice.test", "#bell%07@alice.test"),
+            ("café", "alice.test", "#café@alice.test"),
+        ] {
+            let channel = ChannelName::new(room, handle);
+            assert_eq!(channel.0, name);
+            let parsed = Some((room.to_string(), handle.to_ascii_lowercase()));
+            assert_eq!(channel.parse(), parsed);
+            assert_eq!(
+                channel.folded().parse(),
+                parsed.map(|(r, h)| (r.to_ascii_lowercase(), h))
+            );
+        }
+
+        assert_eq!(
+            ChannelName::new("General Chat", "alice.test").folded().0,
+            "#general%20chat@alice.test"
+        );
+        for name in [
+            "general@alice.test",
+            "#@alice.test",
+            "#general@",
+            "#general",
+            "#a%2@alice.test",
+            "#a%zz@alice.test",
+            "#%FF@alice.test",
+        ] {
+            assert_eq!(ChannelName(name.to_string()).parse(), None, "{name}");
+        }
+    }
+
+    fn room(name: &str) -> psky::Room {
+        psky::Room {
+            name: name.to_string(),
+            languages: None,
+            topic: None,
+            tags: None,
+            allowlist: None,
+            denylist: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn renamed_rooms_go_by_the_new_name() {
+        let config = settings();
+        let http = Http::new(&config.atproto).unwrap();
+        let ircsky = Ircsky::with_resolver(config, http, Arc::new(Fake::new(None)));
+        let uri = ChannelUri {
+            did: Did::parse("did:plc:alice").unwrap(),
+            rkey: RecordKey::parse("general").unwrap(),
+        };
+
+        ircsky.add_channel(uri.clone(), "alice.test", room("General"));
+        ircsky.add_channel(uri.clone(), "alice.test", room("lobby"));
+
+        let name = ChannelName::new("lobby", "alice.test");
+        assert_eq!(ircsky.channels.get(&uri).unwrap().name, name);
+        assert_eq!(*ircsky.channel_name_map.get(&name).unwrap(), uri);
+        assert!(ircsky
+            .channel_name_map
+            .get(&ChannelName::new("general", "alice.test"))
+            .is_none());
+
+        // only the case changed, so the name is still theirs
+        ircsky.add_channel(uri.clone(), "alice.test", room("Lobby"));
+        assert_eq!(*ircsky.channel_name_map.get(&name).unwrap(), uri);
+        assert_eq!(ircsky.channel_name_map.len(), 1);
+    }
 }
@@ -1,8 +1,9 @@
 use anyhow::Result;
 use fastwebsockets::{Frame, OpCode};
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
 
+use crate::aturi::{AtUri, Nsid, RecordKey};
+use crate::did::Did;
 use crate::ircsky;
 use crate::psky;
 use crate::websocket::{self, FrameStream};
@@ -36,7 +37,7 @@ pub struct Commit {
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Event {
-    pub did: String,
+    pub did: Did,
     pub time_us: u64,
     pub kind: String,
     pub commit: Option<Commit>,
@@ -54,6 +55,10 @@ impl ircsky::Ircsky {
 wantedCollections=social.psky.actor.profile&wantedCollections=social.psky.chat.room",
             );
 
+            if let Some(ref collection) = self.config.psky.dm_collection {
+                path.push_str(&format!("&wantedCollections={}", collection));
+            }
+
             if let Some(cursor) = last_time {
                 path.push_str(&format!("&cursor={}", cursor));
             }
@@ -83,8 +88,15 @@ wantedCollections=social.psky.actor.profile&wantedCollections=social.psky.chat.r
                 match msg.opcode {
                     OpCode::Text => {
                         let text = String::from_utf8_lossy(&msg.payload);
-                        let event: Event =
-                            serde_json::from_str(&text).expect("Failed to parse JSON");
+                        // one malformed event, a bad DID say, isn't worth
+                        // dropping the connection over
+                        let event: Event = match serde_json::from_str(&text) {
+                            Ok(event) => event,
+                            Err(e) => {
+                                println!("skipping jetstream event: {e}");
+                                continue;
+                            }
+                        };
                         last_time = Some(self.handle_event(event).await);
                     }
                     OpCode::Close => {
@@ -105,11 +117,36 @@ wantedCollections=social.psky.actor.profile&wantedCollections=social.psky.chat.r
 
         if event.kind == "identity" {
             let handle = event.identity.as_ref().and_then(|i| i.handle.clone());
+            self.invalidate_identity(&event.did, handle.as_deref());
+
+            // the event only claims the handle, it has to resolve back to them
+            let verified = match &handle {
+                Some(handle) => self
+                    .resolve_handle(handle)
+                    .await
+                    .is_ok_and(|did| did == event.did),
+                None => false,
+            };
 
-            // TODO: send to user's channels
             // TODO: update their rooms??
-            self.users
-                .alter(&event.did, |_, old| ircsky::User { handle, ..old });
+            self.update_handle(&event.did, handle.filter(|_| verified));
+        }
+
+        if event.kind == "account" {
+            if let Some(active) = event.account.as_ref().map(|account| account.active) {
+                let mut changed = false;
+                self.users.alter(&event.did, |_, old| {
+                    changed = old.active != active;
+                    ircsky::User { active, ..old }
+                });
+
+                let user = self.users.get(&event.did).map(|user| user.clone());
+                if let (true, Some(user)) = (changed, user) {
+                    self.broadcast_to_user_channels(&event.did, |name, names| {
+                        psky::PskyEvent::Account(user.clone(), name, names)
+                    });
+                }
+            }
         }
 
         if event.kind != "commit" {
@@ -123,6 +160,8 @@ wantedCollections=social.psky.actor.profile&wantedCollections=social.psky.chat.r
                     "social.psky.actor.profile" => {
                         let profile: Option<psky::Profile> = serde_json::from_value(record).ok();
 
+                        self.identity.profiles.insert(&event.did, profile.clone());
+
                         // TODO: send to user's channels
                         self.users
                             .alter(&event.did, |_, old| ircsky::User { profile, ..old });
@@ -147,28 +186,16 @@ wantedCollections=social.psky.actor.profile&wantedCollections=social.psky.chat.r
                                 return ret;
                             }
                         };
-                        let mut entry = self
-                            .channels
-                            .entry(ircsky::ChannelUri(format!(
-                                "at://{}/social.psky.chat.room/{}",
-                                &event.did,
-                                &commit.rkey.as_ref().unwrap()
-                            )))
-                            .or_insert_with(|| ircsky::Channel {
-                                uri: ircsky::ChannelUri(format!(
-                                    "at://{}/social.psky.chat.room/{}",
-                                    &event.did,
-                                    &commit.rkey.as_ref().unwrap()
-                                )),
-                                name: ircsky::ChannelName(format!("#{}@{}", &handle, &room.name)),
-                                sender: tokio::sync::broadcast::channel(16).0,
-                                users: HashSet::new(),
-                                room: room.clone(),
-                            });
-                        if entry.room != room {
-                            // TODO: handle room updates (what about name? actually what about user handle change?)
-                            entry.room = room.clone();
-                        }
+                        let uri = match commit.rkey.as_deref().map(RecordKey::parse) {
+                            Some(Ok(rkey)) => ircsky::ChannelUri {
+                                did: event.did.clone(),
+                                rkey,
+                            },
+                            _ => {
+                                return ret;
+                            }
+                        };
+                        self.add_channel(uri, &handle, room);
                     }
                     "social.psky.chat.message" => {
                         let user = match self.get_user(&event.did).await {
@@ -184,22 +211,64 @@ wantedCollections=social.psky.actor.profile&wantedCollections=social.psky.chat.r
                                 return ret;
                             }
                         };
+                        // a malformed room fails the parse above, this drops
+                        // references to anything but a room
+                        let room = match ircsky::ChannelUri::try_from(&message.room) {
+                            Ok(room) => room,
+                            Err(_) => {
+                                return ret;
+                            }
+                        };
 
-                        self.channels
-                            .alter(&message.room.clone(), |_, mut channel| {
-                                channel.users.insert(user.did.clone()).then(|| {
-                                    let _ = channel.sender.send(psky::PskyEvent::Join(
-                                        user.clone(),
-                                        channel.name.clone(),
-                                    ));
-                                });
-                                let _ = channel.sender.send(psky::PskyEvent::PrivateMessage(
-                                    user,
-                                    message,
+                        let meta = psky::MessageMeta {
+                            msgid: record_uri(&event.did, collection, commit.rkey.as_deref()),
+                            time_us: event.time_us,
+                        };
+                        let history_len = self.config.irc.chathistory;
+
+                        self.channels.alter(&room, |_, mut channel| {
+                            channel.users.insert(user.did.clone()).then(|| {
+                                let _ = channel.sender.send(psky::PskyEvent::Join(
+                                    user.clone(),
                                     channel.name.clone(),
                                 ));
-                                channel
                             });
+                            channel.history.push_back(ircsky::HistoryEntry {
+                                user: user.clone(),
+                                content: message.content.clone(),
+                                meta: meta.clone(),
+                            });
+                            while channel.history.len() > history_len {
+                                channel.history.pop_front();
+                            }
+                            let _ = channel.sender.send(psky::PskyEvent::PrivateMessage(
+                                user,
+                                message,
+                                channel.name.clone(),
+                                meta,
+                            ));
+                            channel
+                        });
+                    }
+                    collection if Some(collection) == self.config.psky.dm_collection.as_deref() => {
+                        let message: psky::DirectMessage = match serde_json::from_value(record) {
+                            Ok(msg) => msg,
+                            Err(_) => {
+                                return ret;
+                            }
+                        };
+                        let user = match self.get_user(&event.did).await {
+                            Ok(user) => user.0.as_ref().to_owned(),
+                            Err(_) => {
+                                return ret;
+                            }
+                        };
+
+                        let meta = psky::MessageMeta {
+                            msgid: record_uri(&event.did, collection, commit.rkey.as_deref()),
+                            time_us: event.time_us,
+                        };
+                        self.deliver_dm(user, &message.recipient, message.content, meta);
                     }
                     _ => {}
                 }
@@ -209,3 +278,13 @@ wantedCollections=social.psky.actor.profile&wantedCollections=social.psky.chat.r
         ret
     }
 }
+
+// the msgid of a message record
+fn record_uri(did: &Did, collection: &str, rkey: Option<&str>) -> Option<String> {
+    let uri = AtUri::record(
+        did.clone(),
+        Nsid::parse(collection).ok()?,
+        RecordKey::parse(rkey?).ok()?,
+    );
+    Some(uri.to_string())
+}
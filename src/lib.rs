@@ -1,9 +1,20 @@
 mod atproto;
+mod aturi;
 mod config;
+mod did;
+mod dm;
+mod http;
+mod identity;
 mod irc;
 mod ircsky;
 mod jetstream;
+mod metrics;
+mod nick;
+mod oauth;
 mod psky;
+mod ratelimit;
+mod resolver;
+mod session;
 mod websocket;
 
 pub use config::get_config;
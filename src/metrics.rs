@@ -0,0 +1,23 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+#[derive(Default, Debug)]
+pub struct Metrics {
+    pub clients: AtomicUsize,
+    pub sendq_bytes: AtomicUsize,
+    pub sendq_lines: AtomicUsize,
+    pub sendq_peak: AtomicUsize,
+    pub sendq_exceeded: AtomicUsize,
+}
+
+impl Metrics {
+    pub fn queued(&self, bytes: usize) {
+        self.sendq_lines.fetch_add(1, Ordering::Relaxed);
+        let total = self.sendq_bytes.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        self.sendq_peak.fetch_max(total, Ordering::Relaxed);
+    }
+
+    pub fn dequeued(&self, lines: usize, bytes: usize) {
+        self.sendq_lines.fetch_sub(lines, Ordering::Relaxed);
+        self.sendq_bytes.fetch_sub(bytes, Ordering::Relaxed);
+    }
+}
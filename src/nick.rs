@@ -0,0 +1,182 @@
+use std::fmt::Write;
+
+use crate::atproto::MAX_HANDLE_LEN;
+
+// every handle fits, escaping and DIDs may need shortening
+pub const NICKLEN: usize = MAX_HANDLE_LEN;
+
+// neither can appear in a handle or a DID, so they can't be mistaken for one:
+// | starts an escaped byte as |hh, ^ ends a shortened nick with a hash of the DID
+const ESCAPE: char = '|';
+const SHORTENED: char = '^';
+
+const SPECIAL: &str = "-._[]\\`{}";
+
+fn allowed(c: char, first: bool) -> bool {
+    if first && (c.is_ascii_digit() || c == '-') {
+        return false;
+    }
+    c.is_ascii_alphanumeric() || SPECIAL.contains(c)
+}
+
+// handles are [a-z0-9.-] and go through unchanged unless they start with a
+// digit, DIDs have their : turned into _, anything else is escaped
+fn escape(id: &str) -> String {
+    let mut nick = String::with_capacity(id.len());
+    for (i, c) in id.chars().enumerate() {
+        match c {
+            ':' => nick.push('_'),
+            c if c != '_' && allowed(c, i == 0) => nick.push(c),
+            c => {
+                for b in c.to_string().bytes() {
+                    _ = write!(nick, "{ESCAPE}{b:02x}");
+                }
+            }
+        }
+    }
+    nick
+}
+
+// FNV-1a, stable across builds unlike the std hasher
+fn hash(s: &str) -> u32 {
+    s.bytes().fold(0x811c9dc5, |hash, b| {
+        (hash ^ b as u32).wrapping_mul(0x01000193)
+    })
+}
+
+// the nick for an identity, the verified handle if it has one, else the DID
+pub fn from_identity(handle: Option<&str>, did: &str) -> String {
+    let nick = escape(handle.unwrap_or(did));
+    if nick.len() <= NICKLEN {
+        return nick;
+    }
+
+    // escaped output is ascii, so this can't split a character
+    let keep = NICKLEN - 1 - 8;
+    format!("{}{SHORTENED}{:08x}", &nick[..keep], hash(did))
+}
+
+// the handle or DID a nick was made from, None if it was shortened or isn't
+// one of ours, those can only be found among known users. keeps case, as DIDs
+// can be case-sensitive, nicks are only folded when compared
+pub fn unescape(nick: &str) -> Option<String> {
+    if nick.contains(SHORTENED) {
+        return None;
+    }
+
+    let mut bytes = Vec::with_capacity(nick.len());
+    let mut rest = nick.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        rest = tail;
+        match b {
+            b'_' => bytes.push(b':'),
+            b'|' => {
+                let hex = std::str::from_utf8(rest.get(..2)?).ok()?;
+                bytes.push(u8::from_str_radix(hex, 16).ok()?);
+                rest = &rest[2..];
+            }
+            b => bytes.push(b),
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+// CASEMAPPING=ascii
+pub fn eq(a: &str, b: &str) -> bool {
+    a.eq_ignore_ascii_case(b)
+}
+
+// for nicks picked by clients, which can't use the reserved characters or
+// look like a handle or DID
+pub fn is_valid(nick: &str) -> bool {
+    !nick.is_empty()
+        && nick.len() <= NICKLEN
+        && !nick.contains('.')
+        && !nick
+            .get(..4)
+            .is_some_and(|prefix| prefix.eq_ignore_ascii_case("did_"))
+        && nick.chars().enumerate().all(|(i, c)| allowed(c, i == 0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips() {
+        let ids = [
+            ("alice.bsky.social", "alice.bsky.social"),
+            ("1password.com", "|31password.com"),
+            (
+                "did:plc:ewvi7nxzyoun6zhxrhs64oiz",
+                "did_plc_ewvi7nxzyoun6zhxrhs64oiz",
+            ),
+            ("did:web:Example.com%3A8080", "did_web_Example.com|253A8080"),
+            (
+                "did:web:example.com:u:Bob_1",
+                "did_web_example.com_u_Bob|5f1",
+            ),
+        ];
+        for (id, nick) in ids {
+            assert_eq!(escape(id), nick);
+            assert_eq!(unescape(nick).as_deref(), Some(id));
+            assert_eq!(from_identity(None, id), nick);
+        }
+    }
+
+    #[test]
+    fn prefers_the_handle() {
+        let nick = from_identity(Some("alice.test"), "did:plc:alice");
+        assert_eq!(nick, "alice.test");
+    }
+
+    #[test]
+    fn shortens_long_dids() {
+        let did = format!("did:web:{}.example.com", "a".repeat(300));
+        let nick = from_identity(None, &did);
+
+        assert_eq!(nick.len(), NICKLEN);
+        assert_eq!(nick.split_once(SHORTENED).unwrap().1.len(), 8);
+        assert_eq!(nick, from_identity(None, &did));
+        assert_ne!(nick, from_identity(None, &format!("{did}.org")));
+        assert_eq!(unescape(&nick), None);
+    }
+
+    #[test]
+    fn rejects_malformed_escapes() {
+        assert_eq!(unescape("alice|"), None);
+        assert_eq!(unescape("alice|zz"), None);
+        assert_eq!(unescape("|ff"), None);
+    }
+
+    #[test]
+    fn compares_ignoring_case() {
+        assert!(eq("Alice.Test", "alice.test"));
+        assert!(!eq("alice.test", "alice.tes"));
+    }
+
+    #[test]
+    fn client_nicks() {
+        let long = "a".repeat(NICKLEN + 1);
+        let valid = ["alice", "Alice", "a-1", "[away]", "x_y", "`b{c}\\"];
+        let invalid = [
+            "",
+            "1alice",
+            "-alice",
+            "alice.test",
+            "did_plc_alice",
+            "DID_x",
+            "a|b",
+            "a^b",
+            "a b",
+            "été",
+            long.as_str(),
+        ];
+        for nick in valid {
+            assert!(is_valid(nick), "{nick}");
+        }
+        for nick in invalid {
+            assert!(!is_valid(nick), "{nick}");
+        }
+    }
+}
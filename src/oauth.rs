@@ -0,0 +1,560 @@
+use anyhow::Result;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use bytes::Bytes;
+use dashmap::DashMap;
+use http_body_util::Full;
+use hyper::{server::conn::http1, service::service_fn, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use p256::ecdsa::{signature::Signer, Signature, SigningKey};
+use rand::{rngs::OsRng, RngCore};
+use sha2::{Digest, Sha256};
+use std::convert::Infallible;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tokio::time::Instant;
+
+use atrium_api::com::atproto::repo::create_record;
+
+use crate::atproto;
+use crate::config::OAuthSettings;
+use crate::did::Did;
+use crate::http::Http;
+use crate::ircsky::Ircsky;
+
+const SCOPE: &str = "atproto transition:generic";
+
+fn encode(bytes: impl AsRef<[u8]>) -> String {
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+fn random_string(len: usize) -> String {
+    let mut bytes = vec![0; len];
+    OsRng.fill_bytes(&mut bytes);
+    encode(bytes)
+}
+
+fn sha256(value: &str) -> String {
+    encode(Sha256::digest(value.as_bytes()))
+}
+
+// every request of a session carries a proof signed with the same key, with
+// the latest nonce the server gave us
+struct Dpop {
+    key: SigningKey,
+    nonce: Mutex<Option<String>>,
+}
+
+impl Dpop {
+    fn new() -> Self {
+        Self {
+            key: SigningKey::random(&mut OsRng),
+            nonce: Mutex::new(None),
+        }
+    }
+
+    fn proof(&self, method: &str, url: &str, access_token: Option<&str>) -> Result<String> {
+        let point = self.key.verifying_key().to_encoded_point(false);
+        let (x, y) = point
+            .x()
+            .zip(point.y())
+            .ok_or(anyhow::anyhow!("DPoP key has no coordinates"))?;
+
+        let header = serde_json::json!({
+            "typ": "dpop+jwt",
+            "alg": "ES256",
+            "jwk": { "kty": "EC", "crv": "P-256", "x": encode(x), "y": encode(y) },
+        });
+        let mut claims = serde_json::json!({
+            "jti": random_string(16),
+            "htm": method,
+            "htu": url,
+            "iat": chrono::Utc::now().timestamp(),
+        });
+        if let Some(nonce) = self.nonce.lock().unwrap().clone() {
+            claims["nonce"] = nonce.into();
+        }
+        if let Some(access_token) = access_token {
+            claims["ath"] = sha256(access_token).into();
+        }
+
+        let input = format!(
+            "{}.{}",
+            encode(header.to_string()),
+            encode(claims.to_string())
+        );
+        let signature: Signature = self.key.sign(input.as_bytes());
+        Ok(format!("{input}.{}", encode(signature.to_bytes())))
+    }
+
+    // retries once when the server rejects the proof and hands out a new nonce
+    async fn send(
+        &self,
+        request: impl Fn() -> reqwest::RequestBuilder,
+        method: &str,
+        url: &str,
+        access_token: Option<&str>,
+    ) -> Result<reqwest::Response> {
+        let mut retried = false;
+        loop {
+            let response = request()
+                .header("DPoP", self.proof(method, url, access_token)?)
+                .send()
+                .await?;
+
+            let nonce = response
+                .headers()
+                .get("DPoP-Nonce")
+                .and_then(|nonce| nonce.to_str().ok())
+                .map(str::to_owned);
+            let new_nonce = nonce.is_some() && nonce != *self.nonce.lock().unwrap();
+            if nonce.is_some() {
+                *self.nonce.lock().unwrap() = nonce;
+            }
+
+            let rejected = matches!(
+                response.status(),
+                reqwest::StatusCode::BAD_REQUEST | reqwest::StatusCode::UNAUTHORIZED
+            );
+            if retried || !rejected || !new_nonce {
+                return Ok(response);
+            }
+            retried = true;
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct AuthServerMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    pushed_authorization_request_endpoint: String,
+}
+
+#[derive(serde::Deserialize)]
+struct ParResponse {
+    request_uri: String,
+    expires_in: u64,
+}
+
+#[derive(serde::Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    refresh_token: Option<String>,
+    sub: String,
+    expires_in: Option<u64>,
+}
+
+async fn json_or_error<R: serde::de::DeserializeOwned>(response: reqwest::Response) -> Result<R> {
+    if !response.status().is_success() {
+        anyhow::bail!(
+            "{} from {}: {}",
+            response.status(),
+            response.url().clone(),
+            response.text().await.unwrap_or_default()
+        );
+    }
+    Ok(response.json().await?)
+}
+
+// the code and issuer the authorization server redirected back with
+type Approval = std::result::Result<(String, String), String>;
+
+// a login waiting for its callback, by the address that started it
+struct Pending {
+    tx: oneshot::Sender<Approval>,
+    ip: IpAddr,
+    expires_at: Instant,
+}
+
+pub struct OAuth {
+    settings: OAuthSettings,
+    http: Http,
+    pending: DashMap<String, Pending>,
+}
+
+impl OAuth {
+    pub fn new(settings: OAuthSettings, http: Http) -> Self {
+        Self {
+            settings,
+            http,
+            pending: DashMap::new(),
+        }
+    }
+
+    fn client_id(&self) -> String {
+        format!("{}/oauth/client-metadata.json", self.public_url())
+    }
+
+    fn redirect_uri(&self) -> String {
+        format!("{}/oauth/callback", self.public_url())
+    }
+
+    fn public_url(&self) -> &str {
+        self.settings.public_url.trim_end_matches('/')
+    }
+
+    fn client_metadata(&self) -> serde_json::Value {
+        serde_json::json!({
+            "client_id": self.client_id(),
+            "client_name": "ircsky",
+            "client_uri": self.public_url(),
+            "application_type": "web",
+            "grant_types": ["authorization_code", "refresh_token"],
+            "response_types": ["code"],
+            "redirect_uris": [self.redirect_uri()],
+            "scope": SCOPE,
+            "token_endpoint_auth_method": "none",
+            "dpop_bound_access_tokens": true,
+        })
+    }
+
+    // pushes an authorization request for the handle resolved to the DID on
+    // the PDS, the user approves it at the returned login's url
+    pub async fn authorize(
+        self: &Arc<Self>,
+        handle: &str,
+        did: &Did,
+        pds: &str,
+        ip: IpAddr,
+    ) -> Result<PendingLogin> {
+        self.prune();
+        let pending_from_ip = self.pending.iter().filter(|p| p.ip == ip).count();
+        if pending_from_ip >= self.settings.max_pending_per_ip {
+            anyhow::bail!("Too many logins pending from your address, try again later");
+        }
+
+        let (did, pds) = (did.clone(), pds.to_string());
+        let auth_server = atproto::get_auth_endpoint(&self.http, &pds).await?;
+
+        let metadata: AuthServerMetadata = json_or_error(
+            self.http
+                .get(&format!(
+                    "{}/.well-known/oauth-authorization-server",
+                    auth_server.trim_end_matches('/')
+                ))
+                .await?,
+        )
+        .await?;
+
+        let verifier = random_string(32);
+        let state = random_string(16);
+        let dpop = Dpop::new();
+
+        let par_endpoint = metadata.pushed_authorization_request_endpoint.clone();
+        let params = [
+            ("client_id", self.client_id()),
+            ("response_type", "code".to_string()),
+            ("redirect_uri", self.redirect_uri()),
+            ("scope", SCOPE.to_string()),
+            ("state", state.clone()),
+            ("code_challenge", sha256(&verifier)),
+            ("code_challenge_method", "S256".to_string()),
+            ("login_hint", handle.to_string()),
+        ];
+        let par: ParResponse = json_or_error(
+            dpop.send(
+                || self.http.client().post(&par_endpoint).form(&params),
+                "POST",
+                &par_endpoint,
+                None,
+            )
+            .await?,
+        )
+        .await?;
+
+        let url = reqwest::Url::parse_with_params(
+            &metadata.authorization_endpoint,
+            [
+                ("client_id", self.client_id()),
+                ("request_uri", par.request_uri),
+            ],
+        )?;
+
+        let expires_at = Instant::now() + Duration::from_secs(par.expires_in);
+        let (tx, rx) = oneshot::channel();
+        self.pending
+            .insert(state.clone(), Pending { tx, ip, expires_at });
+
+        Ok(PendingLogin {
+            oauth: self.clone(),
+            url: url.to_string(),
+            did,
+            pds,
+            state,
+            expires_at,
+            approval: rx,
+            verifier,
+            dpop: Arc::new(dpop),
+            metadata,
+        })
+    }
+
+    // logins whose request has expired can't be approved anymore
+    fn prune(&self) {
+        let now = Instant::now();
+        self.pending.retain(|_, pending| pending.expires_at > now);
+    }
+
+    // hands the redirect's parameters to the login waiting for them
+    fn callback(&self, query: &str) -> (StatusCode, &'static str) {
+        let url = match reqwest::Url::parse(&format!("http://callback/?{query}")) {
+            Ok(url) => url,
+            Err(_) => return (StatusCode::BAD_REQUEST, "Invalid callback"),
+        };
+        let param = |name: &str| {
+            url.query_pairs()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.into_owned())
+        };
+
+        let pending = param("state").and_then(|state| self.pending.remove(&state));
+        let tx = match pending {
+            Some((_, pending)) if pending.expires_at > Instant::now() => pending.tx,
+            _ => {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    "This login has expired, or was already used",
+                )
+            }
+        };
+
+        let approval = match (param("code"), param("iss")) {
+            (Some(code), Some(iss)) => Ok((code, iss)),
+            _ => Err(param("error_description")
+                .or(param("error"))
+                .unwrap_or_else(|| "No code given".to_string())),
+        };
+        let approved = approval.is_ok();
+
+        match tx.send(approval) {
+            Ok(()) if approved => (StatusCode::OK, "Logged in, you can go back to IRC now"),
+            Ok(()) => (StatusCode::OK, "Login denied"),
+            Err(_) => (StatusCode::GONE, "Your IRC connection has closed"),
+        }
+    }
+
+    fn forget(&self, state: &str) {
+        self.pending.remove(state);
+    }
+
+    async fn handle_request(
+        &self,
+        request: Request<hyper::body::Incoming>,
+    ) -> Response<Full<Bytes>> {
+        let (status, content_type, body) = match request.uri().path() {
+            "/oauth/client-metadata.json" => (
+                StatusCode::OK,
+                "application/json",
+                self.client_metadata().to_string(),
+            ),
+            "/oauth/callback" => {
+                let (status, body) = self.callback(request.uri().query().unwrap_or_default());
+                (status, "text/plain; charset=utf-8", body.to_string())
+            }
+            _ => (
+                StatusCode::NOT_FOUND,
+                "text/plain; charset=utf-8",
+                "Not found".to_string(),
+            ),
+        };
+
+        let mut response = Response::new(Full::new(Bytes::from(body)));
+        *response.status_mut() = status;
+        if let Ok(content_type) = content_type.parse() {
+            response
+                .headers_mut()
+                .insert(hyper::header::CONTENT_TYPE, content_type);
+        }
+        response
+    }
+}
+
+// an authorization request the user hasn't approved yet, forgotten when
+// dropped so abandoned logins don't pile up
+pub struct PendingLogin {
+    oauth: Arc<OAuth>,
+    pub url: String,
+    pub did: Did,
+    pds: String,
+    state: String,
+    pub expires_at: Instant,
+    approval: oneshot::Receiver<Approval>,
+    verifier: String,
+    dpop: Arc<Dpop>,
+    metadata: AuthServerMetadata,
+}
+
+impl PendingLogin {
+    // the authorization code, once the user approved
+    pub async fn approved(&mut self) -> Result<(String, String)> {
+        match tokio::time::timeout_at(self.expires_at, &mut self.approval).await {
+            Ok(Ok(Ok(approval))) => Ok(approval),
+            Ok(Ok(Err(error))) => anyhow::bail!("Login failed: {error}"),
+            Ok(Err(_)) => anyhow::bail!("Login was abandoned"),
+            Err(_) => anyhow::bail!("Login expired"),
+        }
+    }
+
+    pub async fn complete(&self, code: &str, iss: &str) -> Result<OAuthSession> {
+        let oauth = &self.oauth;
+        if iss != self.metadata.issuer {
+            anyhow::bail!("Login was approved by the wrong issuer");
+        }
+
+        let token_endpoint = self.metadata.token_endpoint.clone();
+        let params = [
+            ("grant_type", "authorization_code".to_string()),
+            ("code", code.to_string()),
+            ("redirect_uri", oauth.redirect_uri()),
+            ("client_id", oauth.client_id()),
+            ("code_verifier", self.verifier.clone()),
+        ];
+        let tokens: TokenResponse = json_or_error(
+            self.dpop
+                .send(
+                    || oauth.http.client().post(&token_endpoint).form(&params),
+                    "POST",
+                    &token_endpoint,
+                    None,
+                )
+                .await?,
+        )
+        .await?;
+
+        if self.did != *tokens.sub {
+            anyhow::bail!("Login was approved for a different account");
+        }
+
+        Ok(OAuthSession {
+            did: self.did.clone(),
+            pds: self.pds.clone(),
+            client_id: oauth.client_id(),
+            http: oauth.http.client().clone(),
+            token_endpoint,
+            dpop: self.dpop.clone(),
+            expires_at: expires_at(tokens.expires_in),
+            access_token: tokens.access_token,
+            refresh_token: tokens.refresh_token,
+        })
+    }
+}
+
+impl Drop for PendingLogin {
+    fn drop(&mut self) {
+        self.oauth.forget(&self.state);
+    }
+}
+
+fn expires_at(expires_in: Option<u64>) -> Option<Instant> {
+    // refresh a little early, so requests don't race the expiry
+    expires_in.map(|secs| Instant::now() + Duration::from_secs(secs.saturating_sub(30)))
+}
+
+pub struct OAuthSession {
+    pub did: Did,
+    pds: String,
+    client_id: String,
+    http: reqwest::Client,
+    token_endpoint: String,
+    dpop: Arc<Dpop>,
+    access_token: String,
+    refresh_token: Option<String>,
+    expires_at: Option<Instant>,
+}
+
+impl OAuthSession {
+    async fn refresh(&mut self) -> Result<()> {
+        let refresh_token = match self.refresh_token {
+            Some(ref refresh_token) => refresh_token.clone(),
+            None => anyhow::bail!("Session expired, log in again"),
+        };
+
+        let params = [
+            ("grant_type", "refresh_token".to_string()),
+            ("refresh_token", refresh_token),
+            ("client_id", self.client_id.clone()),
+        ];
+        let tokens: TokenResponse = json_or_error(
+            self.dpop
+                .send(
+                    || self.http.post(&self.token_endpoint).form(&params),
+                    "POST",
+                    &self.token_endpoint,
+                    None,
+                )
+                .await?,
+        )
+        .await?;
+
+        if self.did != *tokens.sub {
+            anyhow::bail!("Session was refreshed for a different account");
+        }
+
+        self.access_token = tokens.access_token;
+        self.refresh_token = tokens.refresh_token.or(self.refresh_token.take());
+        self.expires_at = expires_at(tokens.expires_in);
+        Ok(())
+    }
+
+    pub async fn create_record(&mut self, input: create_record::InputData) -> Result<()> {
+        if self.expires_at.is_some_and(|at| at <= Instant::now()) {
+            self.refresh().await?;
+        }
+
+        let url = format!(
+            "{}/xrpc/com.atproto.repo.createRecord",
+            self.pds.trim_end_matches('/')
+        );
+        let response = self
+            .dpop
+            .send(
+                || {
+                    self.http
+                        .post(&url)
+                        .header("Authorization", format!("DPoP {}", self.access_token))
+                        .json(&input)
+                },
+                "POST",
+                &url,
+                Some(&self.access_token),
+            )
+            .await?;
+
+        json_or_error::<serde_json::Value>(response).await?;
+        Ok(())
+    }
+}
+
+impl Ircsky {
+    // serves the client metadata and the redirect callback
+    pub async fn start_oauth_server(self) -> Result<()> {
+        let (oauth, settings) = match (self.oauth.clone(), self.config.oauth.as_ref()) {
+            (Some(oauth), Some(settings)) => (oauth, settings),
+            _ => return Ok(()),
+        };
+
+        let listener =
+            tokio::net::TcpListener::bind((settings.host.as_str(), settings.port)).await?;
+
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let oauth = oauth.clone();
+
+            tokio::spawn(async move {
+                let service = service_fn(move |request| {
+                    let oauth = oauth.clone();
+                    async move { Ok::<_, Infallible>(oauth.handle_request(request).await) }
+                });
+                if let Err(e) = http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service)
+                    .await
+                {
+                    println!("oauth listener error: {e}");
+                }
+            });
+        }
+    }
+}
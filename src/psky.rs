@@ -1,4 +1,6 @@
-use crate::ircsky::{ChannelName, ChannelUri, User};
+use crate::aturi::AtUri;
+use crate::did::Did;
+use crate::ircsky::{ChannelName, User};
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -14,7 +16,18 @@ pub struct Message {
     pub r#type: String,
     pub content: String,
     //pub facets: Option<serde_json::Value>,
-    pub room: ChannelUri,
+    // a room, or the recipient's repo for DMs
+    pub room: AtUri,
+}
+
+// a DM published when the recipient isn't connected, its $type is the
+// configured DM collection. records are public, so this isn't private
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DirectMessage {
+    #[serde(rename = "$type")]
+    pub r#type: String,
+    pub content: String,
+    pub recipient: Did,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
@@ -33,12 +46,44 @@ pub struct ModList {
     users: Vec<String>,
 }
 
+// where a message came from, for msgid and server-time tags
+#[derive(Debug, Clone)]
+pub struct MessageMeta {
+    pub msgid: Option<String>,
+    pub time_us: u64,
+}
+
+impl MessageMeta {
+    pub fn now() -> Self {
+        Self {
+            msgid: None,
+            time_us: u64::try_from(chrono::Utc::now().timestamp_micros()).unwrap_or_default(),
+        }
+    }
+
+    pub fn time(&self) -> String {
+        i64::try_from(self.time_us)
+            .ok()
+            .and_then(chrono::DateTime::from_timestamp_micros)
+            .unwrap_or_default()
+            .to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum PskyEvent {
-    PrivateMessage(User, Message, ChannelName),
+    PrivateMessage(User, Message, ChannelName, MessageMeta),
     //DeleteMessage(User),
     //ProfileUpdate(User, User),
     //HandleUpdate(User, User),
     Join(User, ChannelName),
     Part(User, ChannelName),
+    // reason, the channel this was sent to, and every channel the user left
+    Quit(User, String, ChannelName, Vec<ChannelName>),
+    // like Quit, sent to every channel the user is in
+    Away(User, ChannelName, Vec<ChannelName>),
+    // the user's nick changed from the one given, like Quit
+    Nick(User, String, ChannelName, Vec<ChannelName>),
+    // the user's account status changed, see User::account
+    Account(User, ChannelName, Vec<ChannelName>),
 }
@@ -0,0 +1,88 @@
+use dashmap::DashMap;
+use std::time::Duration;
+use tokio::time::Instant;
+
+use crate::config::FloodSettings;
+
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    capacity: f64,
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(capacity: f64, rate: f64) -> Self {
+        Self {
+            capacity,
+            rate,
+            tokens: capacity,
+            last: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last = now;
+    }
+
+    // goes into debt if empty, returning how long until it's back in credit
+    pub fn take(&mut self) -> Duration {
+        self.refill();
+        self.tokens -= 1.0;
+
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::try_from_secs_f64(-self.tokens / self.rate).unwrap_or(Duration::MAX)
+        }
+    }
+
+    pub fn try_take(&mut self) -> bool {
+        self.refill();
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn is_full(&mut self) -> bool {
+        self.refill();
+        self.tokens >= self.capacity
+    }
+}
+
+// per DID buckets, shared between all of a user's connections
+#[derive(Default)]
+pub struct RateLimits {
+    commands: DashMap<String, TokenBucket>,
+    publish: DashMap<String, TokenBucket>,
+}
+
+impl RateLimits {
+    pub fn command_lag(&self, did: &str, settings: &FloodSettings) -> Duration {
+        self.commands
+            .entry(did.to_string())
+            .or_insert_with(|| settings.command_bucket())
+            .take()
+    }
+
+    pub fn try_publish(&self, did: &str, settings: &FloodSettings) -> bool {
+        self.publish
+            .entry(did.to_string())
+            .or_insert_with(|| settings.publish_bucket())
+            .try_take()
+    }
+
+    // forgets buckets that have refilled, they'd be recreated full anyway
+    pub fn prune(&self) {
+        self.commands.retain(|_, bucket| !bucket.is_full());
+        self.publish.retain(|_, bucket| !bucket.is_full());
+    }
+}
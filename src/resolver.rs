@@ -0,0 +1,317 @@
+use anyhow::Result;
+use futures::future::BoxFuture;
+use hickory_resolver::TokioAsyncResolver;
+
+use crate::atproto::MAX_HANDLE_LEN;
+use crate::config::AtprotoSettings;
+use crate::did::Did;
+use crate::http::Http;
+
+// turns a handle into the DID it claims to be, Ircsky holds one of these so
+// it can be swapped out
+pub trait HandleResolver: Send + Sync {
+    fn resolve<'a>(&'a self, handle: &'a str) -> BoxFuture<'a, Result<Did>>;
+}
+
+// handles are case-insensitive domain names, at least two labels of
+// [a-z0-9-] and a TLD that doesn't start with a digit
+pub fn is_valid_handle(handle: &str) -> bool {
+    let labels = handle.split('.').collect::<Vec<_>>();
+    handle.len() <= MAX_HANDLE_LEN
+        && labels.len() >= 2
+        && labels.iter().all(|label| {
+            (1..=63).contains(&label.len())
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+        && !labels[labels.len() - 1].starts_with(|c: char| c.is_ascii_digit())
+}
+
+fn parse_did(did: &str) -> Option<Did> {
+    Did::parse(did.trim()).ok()
+}
+
+// the atproto-did file is a single DID, anything longer isn't one
+const MAX_WELL_KNOWN_LEN: usize = 2048;
+
+// DNS and HTTPS together, the AppView only if configured and both found nothing
+pub struct NativeResolver {
+    dns: Box<dyn HandleResolver>,
+    well_known: Box<dyn HandleResolver>,
+    appview: Option<Box<dyn HandleResolver>>,
+}
+
+impl NativeResolver {
+    pub fn new(settings: &AtprotoSettings, http: Http) -> Self {
+        let appview = settings.appview_fallback.then(|| {
+            Box::new(AppViewResolver::new(http.clone(), &settings.appview_url))
+                as Box<dyn HandleResolver>
+        });
+        Self::from_parts(
+            Box::new(DnsResolver::new()),
+            Box::new(WellKnownResolver { http }),
+            appview,
+        )
+    }
+
+    pub fn from_parts(
+        dns: Box<dyn HandleResolver>,
+        well_known: Box<dyn HandleResolver>,
+        appview: Option<Box<dyn HandleResolver>>,
+    ) -> Self {
+        Self {
+            dns,
+            well_known,
+            appview,
+        }
+    }
+}
+
+impl HandleResolver for NativeResolver {
+    fn resolve<'a>(&'a self, handle: &'a str) -> BoxFuture<'a, Result<Did>> {
+        Box::pin(async move {
+            let handle = handle.to_ascii_lowercase();
+            if !is_valid_handle(&handle) {
+                anyhow::bail!("Invalid handle {handle}");
+            }
+
+            let (dns, well_known) =
+                tokio::join!(self.dns.resolve(&handle), self.well_known.resolve(&handle));
+            match (dns, well_known) {
+                (Ok(dns), Ok(well_known)) if dns != well_known => {
+                    anyhow::bail!("DNS and HTTPS disagree on the DID of {handle}")
+                }
+                (Ok(did), _) | (_, Ok(did)) => Ok(did),
+                (Err(e), Err(_)) => match &self.appview {
+                    Some(appview) => appview.resolve(&handle).await,
+                    None => Err(e),
+                },
+            }
+        })
+    }
+}
+
+// _atproto.<handle> TXT "did=<did>", more than one DID is an error
+pub struct DnsResolver {
+    dns: Option<TokioAsyncResolver>,
+}
+
+impl DnsResolver {
+    pub fn new() -> Self {
+        let dns = match TokioAsyncResolver::tokio_from_system_conf() {
+            Ok(dns) => Some(dns),
+            Err(e) => {
+                println!("no DNS resolver, handles only resolve over HTTPS: {e}");
+                None
+            }
+        };
+        Self { dns }
+    }
+}
+
+impl HandleResolver for DnsResolver {
+    fn resolve<'a>(&'a self, handle: &'a str) -> BoxFuture<'a, Result<Did>> {
+        Box::pin(async move {
+            let dns = self
+                .dns
+                .as_ref()
+                .ok_or(anyhow::anyhow!("No DNS resolver"))?;
+            let lookup = dns.txt_lookup(format!("_atproto.{handle}.")).await?;
+
+            let mut dids = lookup
+                .iter()
+                .map(|txt| {
+                    txt.txt_data()
+                        .iter()
+                        .map(|data| String::from_utf8_lossy(data))
+                        .collect::<String>()
+                })
+                .filter_map(|txt| txt.strip_prefix("did=").and_then(parse_did))
+                .collect::<Vec<_>>();
+            dids.dedup();
+
+            match dids.as_slice() {
+                [did] => Ok(did.clone()),
+                [] => anyhow::bail!("No DID in DNS for {handle}"),
+                _ => anyhow::bail!("More than one DID in DNS for {handle}"),
+            }
+        })
+    }
+}
+
+// https://<handle>/.well-known/atproto-did, the DID as plain text
+pub struct WellKnownResolver {
+    http: Http,
+}
+
+impl HandleResolver for WellKnownResolver {
+    fn resolve<'a>(&'a self, handle: &'a str) -> BoxFuture<'a, Result<Did>> {
+        Box::pin(async move {
+            let mut response = self
+                .http
+                .get(&format!("https://{handle}/.well-known/atproto-did"))
+                .await?
+                .error_for_status()?;
+
+            // content-length is only a hint, the body is capped as it's read
+            let mut body = Vec::new();
+            while let Some(chunk) = response.chunk().await? {
+                if body.len() + chunk.len() > MAX_WELL_KNOWN_LEN {
+                    anyhow::bail!("atproto-did of {handle} is too long");
+                }
+                body.extend_from_slice(&chunk);
+            }
+
+            parse_did(&String::from_utf8_lossy(&body))
+                .ok_or(anyhow::anyhow!("No DID at the well-known of {handle}"))
+        })
+    }
+}
+
+// com.atproto.identity.resolveHandle on an AppView, which has to be trusted
+pub struct AppViewResolver {
+    http: Http,
+    url: String,
+}
+
+impl AppViewResolver {
+    pub fn new(http: Http, url: &str) -> Self {
+        Self {
+            http,
+            url: url.trim_end_matches('/').to_string(),
+        }
+    }
+}
+
+impl HandleResolver for AppViewResolver {
+    fn resolve<'a>(&'a self, handle: &'a str) -> BoxFuture<'a, Result<Did>> {
+        Box::pin(async move {
+            #[derive(serde::Deserialize)]
+            struct HandleResolution {
+                did: Did,
+            }
+
+            let url = reqwest::Url::parse_with_params(
+                &format!("{}/xrpc/com.atproto.identity.resolveHandle", self.url),
+                [("handle", handle)],
+            )?;
+            let resolution: HandleResolution = self
+                .http
+                .get(url.as_str())
+                .await?
+                .error_for_status()?
+                .json()
+                .await?;
+            Ok(resolution.did)
+        })
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    // resolves every handle to the same DID, or fails
+    pub(crate) struct Fake {
+        did: Option<&'static str>,
+        pub(crate) calls: Arc<AtomicUsize>,
+    }
+
+    impl Fake {
+        pub(crate) fn new(did: Option<&'static str>) -> Self {
+            Fake {
+                did,
+                calls: Arc::default(),
+            }
+        }
+
+        fn boxed(did: Option<&'static str>) -> Box<dyn HandleResolver> {
+            Box::new(Fake::new(did))
+        }
+    }
+
+    impl HandleResolver for Fake {
+        fn resolve<'a>(&'a self, handle: &'a str) -> BoxFuture<'a, Result<Did>> {
+            self.calls.fetch_add(1, Ordering::Relaxed);
+            Box::pin(async move {
+                match self.did {
+                    Some(did) => Did::parse(did),
+                    None => anyhow::bail!("{handle} not found"),
+                }
+            })
+        }
+    }
+
+    const ALICE: &str = "did:plc:alice";
+    const MALLORY: &str = "did:plc:mallory";
+
+    async fn resolve(
+        dns: Option<&'static str>,
+        well_known: Option<&'static str>,
+        appview: Option<Option<&'static str>>,
+    ) -> Result<Did> {
+        NativeResolver::from_parts(
+            Fake::boxed(dns),
+            Fake::boxed(well_known),
+            appview.map(Fake::boxed),
+        )
+        .resolve("Alice.test")
+        .await
+    }
+
+    #[tokio::test]
+    async fn dns_and_well_known_agree() {
+        assert_eq!(
+            resolve(Some(ALICE), Some(ALICE), None).await.unwrap(),
+            *ALICE
+        );
+    }
+
+    #[tokio::test]
+    async fn either_method_is_enough() {
+        assert_eq!(resolve(Some(ALICE), None, None).await.unwrap(), *ALICE);
+        assert_eq!(resolve(None, Some(ALICE), None).await.unwrap(), *ALICE);
+    }
+
+    #[tokio::test]
+    async fn dns_and_well_known_disagree() {
+        assert!(resolve(Some(ALICE), Some(MALLORY), None).await.is_err());
+        assert!(resolve(Some(ALICE), Some(MALLORY), Some(Some(ALICE)))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn appview_only_when_both_fail() {
+        assert_eq!(
+            resolve(None, None, Some(Some(ALICE))).await.unwrap(),
+            *ALICE
+        );
+        assert_eq!(
+            resolve(Some(ALICE), None, Some(Some(MALLORY)))
+                .await
+                .unwrap(),
+            *ALICE
+        );
+        assert!(resolve(None, None, None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn invalid_handles_are_not_looked_up() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let fake = || {
+            Box::new(Fake {
+                did: Some(ALICE),
+                calls: calls.clone(),
+            }) as Box<dyn HandleResolver>
+        };
+        let resolver = NativeResolver::from_parts(fake(), fake(), Some(fake()));
+
+        assert!(resolver.resolve("not a handle").await.is_err());
+        assert!(resolver.resolve("localhost").await.is_err());
+        assert_eq!(calls.load(Ordering::Relaxed), 0);
+    }
+}
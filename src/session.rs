@@ -0,0 +1,204 @@
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use anyhow::Result;
+use atrium_api::agent::{
+    store::{MemorySessionStore, SessionStore},
+    AtpAgent,
+};
+use atrium_api::com::atproto::{repo::create_record, server::create_session};
+use atrium_xrpc_client::reqwest::ReqwestClient;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+use crate::config::SessionSettings;
+use crate::oauth::OAuthSession;
+
+// how a logged in user's records get written
+pub enum Session {
+    Password(AtpAgent<StoredSession, ReqwestClient>),
+    OAuth(Box<OAuthSession>),
+}
+
+impl Session {
+    pub async fn create_record(&mut self, input: create_record::InputData) -> Result<()> {
+        match self {
+            Session::Password(agent) => {
+                agent
+                    .api
+                    .com
+                    .atproto
+                    .repo
+                    .create_record(input.into())
+                    .await?;
+                Ok(())
+            }
+            Session::OAuth(session) => session.create_record(input).await,
+        }
+    }
+}
+
+// the agent refreshes its tokens on its own, and hands every new session to
+// its store
+#[derive(Clone)]
+pub enum StoredSession {
+    Memory(MemorySessionStore),
+    File(Arc<FileSessionStore>),
+}
+
+impl StoredSession {
+    pub fn new(settings: Option<&SessionSettings>, did: &str, password: &str) -> Result<Self> {
+        Ok(match settings {
+            Some(settings) => {
+                StoredSession::File(Arc::new(FileSessionStore::open(settings, did, password)?))
+            }
+            None => StoredSession::Memory(MemorySessionStore::default()),
+        })
+    }
+}
+
+impl SessionStore for StoredSession {
+    async fn get_session(&self) -> Option<create_session::Output> {
+        match self {
+            StoredSession::Memory(store) => store.get_session().await,
+            StoredSession::File(store) => store.get_session().await,
+        }
+    }
+
+    async fn set_session(&self, session: create_session::Output) {
+        match self {
+            StoredSession::Memory(store) => store.set_session(session).await,
+            StoredSession::File(store) => store.set_session(session).await,
+        }
+    }
+
+    async fn clear_session(&self) {
+        match self {
+            StoredSession::Memory(store) => store.clear_session().await,
+            StoredSession::File(store) => store.clear_session().await,
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Stored {
+    // an HMAC of the password the session was created with, a reconnect
+    // only gets the session back with the same password
+    verifier: String,
+    session: create_session::Output,
+}
+
+// one file per DID, encrypted with the server's key
+pub struct FileSessionStore {
+    path: PathBuf,
+    cipher: Aes256Gcm,
+    verifier: String,
+    session: RwLock<Option<create_session::Output>>,
+}
+
+impl FileSessionStore {
+    // loads the DID's stored session if the password matches the one it was
+    // created with
+    fn open(settings: &SessionSettings, did: &str, password: &str) -> Result<Self> {
+        let key = settings.key()?;
+        let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key));
+
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&key)?;
+        mac.update(did.as_bytes());
+        mac.update(b"\0");
+        mac.update(password.as_bytes());
+        let verifier = hex(&mac.finalize().into_bytes());
+
+        let path = settings
+            .dir
+            .join(format!("{}.session", hex(&Sha256::digest(did.as_bytes()))));
+
+        let session = std::fs::read(&path)
+            .ok()
+            .and_then(|data| decrypt(&cipher, &data))
+            .and_then(|data| serde_json::from_slice::<Stored>(&data).ok())
+            .filter(|stored| stored.verifier == verifier)
+            .map(|stored| stored.session);
+
+        Ok(Self {
+            path,
+            cipher,
+            verifier,
+            session: RwLock::new(session),
+        })
+    }
+
+    async fn save(&self, session: &create_session::Output) -> Result<()> {
+        let data = serde_json::to_vec(&Stored {
+            verifier: self.verifier.clone(),
+            session: session.clone(),
+        })?;
+
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let mut file = nonce.to_vec();
+        file.extend(
+            self.cipher
+                .encrypt(&nonce, data.as_slice())
+                .map_err(|_| anyhow::anyhow!("Failed to encrypt session"))?,
+        );
+
+        let (path, tmp) = (self.path.clone(), self.path.with_extension(hex(&nonce)));
+        tokio::task::spawn_blocking(move || {
+            let written = write_private(&path, &tmp, &file);
+            if written.is_err() {
+                _ = std::fs::remove_file(&tmp);
+            }
+            written
+        })
+        .await??;
+        Ok(())
+    }
+}
+
+// through a temporary file only the server can read, so a crash never leaves a
+// half written session behind
+fn write_private(path: &Path, tmp: &Path, data: &[u8]) -> std::io::Result<()> {
+    std::fs::create_dir_all(path.parent().unwrap_or(path))?;
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(tmp)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    std::fs::rename(tmp, path)
+}
+
+impl SessionStore for FileSessionStore {
+    async fn get_session(&self) -> Option<create_session::Output> {
+        self.session.read().await.clone()
+    }
+
+    async fn set_session(&self, session: create_session::Output) {
+        if let Err(e) = self.save(&session).await {
+            println!("failed to store session: {e}");
+        }
+        self.session.write().await.replace(session);
+    }
+
+    async fn clear_session(&self) {
+        _ = tokio::fs::remove_file(&self.path).await;
+        self.session.write().await.take();
+    }
+}
+
+fn decrypt(cipher: &Aes256Gcm, data: &[u8]) -> Option<Vec<u8>> {
+    if data.len() < 12 {
+        return None;
+    }
+    let (nonce, ciphertext) = data.split_at(12);
+    cipher.decrypt(Nonce::from_slice(nonce), ciphertext).ok()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
@@ -67,23 +67,6 @@ pub async fn connect(domain: &str, port: u16, path: &str) -> Result<impl FrameSt
         .header("Sec-WebSocket-Version", "13")
         .body(Empty::<Bytes>::new())?;
 
-    /*
-    dbg!(&req);
-
-    let (mut sender, conn) =
-        hyper::client::conn::http1::handshake(TokioIo::new(tls_stream)).await?;
-
-    let fut = Box::pin(async move {
-        if let Err(e) = conn.with_upgrades().await {
-            eprintln!("Error polling connection: {}", e);
-        }
-    });
-    SpawnExecutor.execute(fut);
-
-    let response = sender.send_request(req).await?;
-    dbg!(&response);
-    */
-
     let (ws, _) = fastwebsockets::handshake::client(&SpawnExecutor, req, tls_stream).await?;
     Ok(FragmentCollector::new(ws))
 }
@@ -91,7 +74,7 @@ pub async fn connect(domain: &str, port: u16, path: &str) -> Result<impl FrameSt
 pub trait FrameStream {
     fn read_frame(
         &mut self,
-    ) -> impl std::future::Future<Output = Result<Frame, WebSocketError>> + Send;
+    ) -> impl std::future::Future<Output = Result<Frame<'_>, WebSocketError>> + Send;
     fn write_frame(
         &mut self,
         frame: Frame,
@@ -99,7 +82,7 @@ pub trait FrameStream {
 }
 
 impl FrameStream for FragmentCollector<hyper_util::rt::TokioIo<hyper::upgrade::Upgraded>> {
-    async fn read_frame(&mut self) -> Result<Frame, WebSocketError> {
+    async fn read_frame(&mut self) -> Result<Frame<'_>, WebSocketError> {
         self.read_frame().await
     }
 